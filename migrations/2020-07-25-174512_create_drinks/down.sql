@@ -0,0 +1,10 @@
+-- This file should undo anything in `up.sql`
+UPDATE users SET price = 0.5 WHERE price IS NULL;
+ALTER TABLE users ALTER COLUMN price SET DEFAULT 0.5;
+ALTER TABLE users ALTER COLUMN price SET NOT NULL;
+ALTER TABLE users ADD COLUMN drink_count SMALLINT NOT NULL default 0;
+UPDATE users SET drink_count = (
+  SELECT COALESCE(SUM(tabs.drink_count), 0) FROM tabs WHERE tabs.user_id = users.id
+);
+DROP TABLE tabs;
+DROP TABLE drinks;
@@ -0,0 +1,32 @@
+-- Your SQL goes here
+CREATE TABLE drinks (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  emoji VARCHAR NOT NULL,
+  price MONEY NOT NULL default 0.5,
+  active BOOLEAN NOT NULL default true
+);
+
+INSERT INTO drinks (name, emoji, price) VALUES
+  ('Bier', '🍺', 1.0),
+  ('Wein', '🍷', 1.5),
+  ('Schnaps', '🥃', 1.0),
+  ('Wasser', '💧', 0.5);
+
+-- One row per user and drink, holding how many of that drink are on the user's tab
+CREATE TABLE tabs (
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  drink_id INTEGER NOT NULL REFERENCES drinks(id),
+  drink_count SMALLINT NOT NULL default 0,
+  PRIMARY KEY (user_id, drink_id)
+);
+
+-- Everything that has been ordered so far was a beer
+INSERT INTO tabs (user_id, drink_id, drink_count)
+  SELECT id, (SELECT id FROM drinks WHERE name = 'Bier'), drink_count
+  FROM users WHERE drink_count > 0;
+
+ALTER TABLE users DROP COLUMN drink_count;
+-- A price on the user overrides the catalog price of every drink
+ALTER TABLE users ALTER COLUMN price DROP NOT NULL;
+ALTER TABLE users ALTER COLUMN price DROP DEFAULT;
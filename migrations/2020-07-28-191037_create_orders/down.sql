@@ -0,0 +1,14 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE tabs (
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  drink_id INTEGER NOT NULL REFERENCES drinks(id),
+  drink_count SMALLINT NOT NULL default 0,
+  PRIMARY KEY (user_id, drink_id)
+);
+
+INSERT INTO tabs (user_id, drink_id, drink_count)
+  SELECT user_id, drink_id, COUNT(*)
+  FROM orders WHERE payment_id IS NULL
+  GROUP BY user_id, drink_id;
+
+DROP TABLE orders;
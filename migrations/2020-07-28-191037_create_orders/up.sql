@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- Every order is a line item of the tab. Orders without a payment_id are still open.
+CREATE TABLE orders (
+  id SERIAL PRIMARY KEY,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  drink_id INTEGER NOT NULL REFERENCES drinks(id),
+  price MONEY NOT NULL,
+  ordered_at TIMESTAMP NOT NULL,
+  payment_id INTEGER REFERENCES payments(id)
+);
+CREATE INDEX orders_user_id_idx ON orders (user_id);
+
+-- Turn the counters into single orders (the time of the original order is unknown)
+INSERT INTO orders (user_id, drink_id, price, ordered_at)
+  SELECT tabs.user_id, tabs.drink_id, COALESCE(users.price, drinks.price), CURRENT_TIMESTAMP
+  FROM tabs
+  JOIN users ON users.id = tabs.user_id
+  JOIN drinks ON drinks.id = tabs.drink_id
+  CROSS JOIN generate_series(1, tabs.drink_count);
+
+DROP TABLE tabs;
@@ -0,0 +1,20 @@
+-- Your SQL goes here
+-- The pubs, that can be chosen as beneficiary of the donations
+-- stripe_account is the id of the connected Stripe account (acct_...) the donation is transfered to
+CREATE TABLE pubs (
+  id SERIAL PRIMARY KEY,
+  name VARCHAR NOT NULL,
+  stripe_account VARCHAR NOT NULL,
+  logo_url VARCHAR,
+  active BOOLEAN NOT NULL default true,
+  -- Donations to an active pub must be transferable
+  CONSTRAINT active_pub_has_account CHECK (NOT active OR stripe_account LIKE 'acct\_%')
+);
+
+-- Li Buddah was the only pub so far. Its account used to be configured via the DESTINATION env-var.
+-- It is inactive until the account is set after running the migration:
+-- UPDATE pubs SET stripe_account = '<DESTINATION>', active = true WHERE name = 'Li Buddah';
+INSERT INTO pubs (name, stripe_account, active) VALUES ('Li Buddah', '', false);
+
+ALTER TABLE users ADD COLUMN pub_id INTEGER REFERENCES pubs(id);
+UPDATE users SET pub_id = (SELECT id FROM pubs WHERE name = 'Li Buddah');
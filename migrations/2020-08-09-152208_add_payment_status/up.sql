@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- Reflects what Stripe reported about the payment (set by the Stripe webhook)
+ALTER TABLE payments ADD COLUMN status VARCHAR NOT NULL default 'received';
+UPDATE payments SET status = 'transferred' WHERE transfer_id IS NOT NULL;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE payments DROP COLUMN status_changed_at;
+ALTER TABLE payments DROP CONSTRAINT payments_status_check;
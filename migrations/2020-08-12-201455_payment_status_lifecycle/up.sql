@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- The status follows the lifecycle of PaymentStatus (see models.rs)
+UPDATE payments SET status = 'received' WHERE status = 'succeeded';
+UPDATE payments SET status = 'transfer_failed' WHERE status = 'received' AND transfer_id IS NULL;
+ALTER TABLE payments ADD CONSTRAINT payments_status_check
+  CHECK (status IN ('received', 'transfer_pending', 'transferred', 'transfer_failed', 'refunded'));
+-- Makes it possible to find payments, that are stuck in a status
+ALTER TABLE payments ADD COLUMN status_changed_at TIMESTAMP NOT NULL default CURRENT_TIMESTAMP;
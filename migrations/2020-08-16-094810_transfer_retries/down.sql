@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE payments DROP COLUMN next_attempt_at;
+ALTER TABLE payments DROP COLUMN last_error;
+ALTER TABLE payments DROP COLUMN transfer_attempts;
+ALTER TABLE payments DROP COLUMN pub_id;
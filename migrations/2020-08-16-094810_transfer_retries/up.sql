@@ -0,0 +1,8 @@
+-- Your SQL goes here
+-- Needed to retry a transfer later on (the pub used to be known only from the invoice payload)
+ALTER TABLE payments ADD COLUMN pub_id INTEGER REFERENCES pubs(id);
+UPDATE payments SET pub_id = (SELECT id FROM pubs WHERE name = 'Li Buddah');
+
+ALTER TABLE payments ADD COLUMN transfer_attempts SMALLINT NOT NULL default 0;
+ALTER TABLE payments ADD COLUMN last_error VARCHAR;
+ALTER TABLE payments ADD COLUMN next_attempt_at TIMESTAMP;
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE payments DROP CONSTRAINT payments_receipt_identifier_key;
+DROP TABLE processed_updates;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- Telegram re-delivers updates, if the webhook was slow or failed.
+-- Every update_id is only processed once.
+CREATE TABLE processed_updates (
+  update_id INTEGER PRIMARY KEY,
+  processed_at TIMESTAMP NOT NULL default CURRENT_TIMESTAMP
+);
+
+-- A charge must never be persisted (and transfered) twice
+ALTER TABLE payments ADD CONSTRAINT payments_receipt_identifier_key UNIQUE (receipt_identifier);
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- Users that have blocked the bot are kept, but marked as inactive
+ALTER TABLE users ADD COLUMN active BOOLEAN NOT NULL default TRUE;
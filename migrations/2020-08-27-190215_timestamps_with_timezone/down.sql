@@ -0,0 +1,17 @@
+-- This file should undo anything in `up.sql`
+-- The timestamps stay in UTC, the old encoding of last_paid and payed_at is not restored
+ALTER TABLE users DROP COLUMN timezone;
+ALTER TABLE pubs DROP COLUMN timezone;
+
+ALTER TABLE processed_updates ALTER COLUMN processed_at TYPE TIMESTAMP
+  USING processed_at AT TIME ZONE 'UTC';
+ALTER TABLE payments ALTER COLUMN next_attempt_at TYPE TIMESTAMP
+  USING next_attempt_at AT TIME ZONE 'UTC';
+ALTER TABLE payments ALTER COLUMN status_changed_at TYPE TIMESTAMP
+  USING status_changed_at AT TIME ZONE 'UTC';
+ALTER TABLE orders ALTER COLUMN ordered_at TYPE TIMESTAMP
+  USING ordered_at AT TIME ZONE 'UTC';
+ALTER TABLE payments ALTER COLUMN payed_at TYPE TIMESTAMP
+  USING payed_at AT TIME ZONE 'UTC';
+ALTER TABLE users ALTER COLUMN last_paid TYPE TIMESTAMP
+  USING last_paid AT TIME ZONE 'UTC';
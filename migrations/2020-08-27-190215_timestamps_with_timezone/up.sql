@@ -0,0 +1,40 @@
+-- Your SQL goes here
+-- All timestamps are stored in UTC. They are only converted to local time for display.
+
+-- last_paid and payed_at were written as seconds since 1970 (shifted by 2 hours),
+-- but read by Postgres as microseconds since 2000. Those values are all before 2000-01-02.
+ALTER TABLE users ALTER COLUMN last_paid DROP DEFAULT;
+ALTER TABLE users ALTER COLUMN last_paid TYPE TIMESTAMPTZ USING
+  CASE WHEN last_paid < TIMESTAMP '2000-01-02'
+    THEN to_timestamp(EXTRACT(EPOCH FROM (last_paid - TIMESTAMP '2000-01-01')) * 1000000 - 7200)
+    ELSE last_paid AT TIME ZONE 'UTC'
+  END;
+ALTER TABLE users ALTER COLUMN last_paid SET DEFAULT CURRENT_TIMESTAMP;
+
+ALTER TABLE payments ALTER COLUMN payed_at TYPE TIMESTAMPTZ USING
+  CASE WHEN payed_at < TIMESTAMP '2000-01-02'
+    THEN to_timestamp(EXTRACT(EPOCH FROM (payed_at - TIMESTAMP '2000-01-01')) * 1000000 - 7200)
+    ELSE payed_at AT TIME ZONE 'UTC'
+  END;
+
+-- ordered_at was shifted by 2 hours as well, but not for the orders created from the old counters.
+-- Those got the CURRENT_TIMESTAMP of the orders migration, which is also the run_on of its
+-- version (diesel records it in the same transaction).
+UPDATE orders SET ordered_at = ordered_at - INTERVAL '2 hours'
+  WHERE ordered_at <> (
+    SELECT run_on FROM __diesel_schema_migrations WHERE version = '20200728191037'
+  );
+ALTER TABLE orders ALTER COLUMN ordered_at TYPE TIMESTAMPTZ
+  USING ordered_at AT TIME ZONE 'UTC';
+
+ALTER TABLE payments ALTER COLUMN status_changed_at TYPE TIMESTAMPTZ
+  USING status_changed_at AT TIME ZONE 'UTC';
+ALTER TABLE payments ALTER COLUMN next_attempt_at TYPE TIMESTAMPTZ
+  USING next_attempt_at AT TIME ZONE 'UTC';
+ALTER TABLE processed_updates ALTER COLUMN processed_at TYPE TIMESTAMPTZ
+  USING processed_at AT TIME ZONE 'UTC';
+
+-- Names of the IANA time zone database (e.g. Europe/Berlin).
+-- The timezone of the user wins over the one of the pub.
+ALTER TABLE pubs ADD COLUMN timezone VARCHAR NOT NULL default 'Europe/Berlin';
+ALTER TABLE users ADD COLUMN timezone VARCHAR;
@@ -0,0 +1,3 @@
+-- Your SQL goes here
+-- NULL for users created before languages were introduced, they get the default language (German)
+ALTER TABLE users ADD COLUMN language VARCHAR;
@@ -0,0 +1,10 @@
+-- Your SQL goes here
+-- The state of the conversation of a user in a chat (e.g. a pending confirmation).
+-- A state older than the timeout of the bot is treated as idle.
+CREATE TABLE conversations (
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  chat_id INTEGER NOT NULL,
+  state VARCHAR NOT NULL default 'idle',
+  state_changed_at TIMESTAMPTZ NOT NULL default CURRENT_TIMESTAMP,
+  PRIMARY KEY (user_id, chat_id)
+);
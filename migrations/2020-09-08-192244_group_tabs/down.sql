@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE group_members;
+
+-- Orders of groups can not be told apart from private ones anymore
+ALTER TABLE orders DROP COLUMN chat_id;
+
+DELETE FROM conversations WHERE chat_id NOT BETWEEN -2147483648 AND 2147483647;
+ALTER TABLE conversations ALTER COLUMN chat_id TYPE INTEGER;
@@ -0,0 +1,17 @@
+-- Your SQL goes here
+-- Ids of (super)groups do not fit into an INTEGER
+ALTER TABLE conversations ALTER COLUMN chat_id TYPE BIGINT;
+
+-- Every chat has its own tab. Until now the bot only chatted privately,
+-- where the chat id is the user id.
+ALTER TABLE orders ADD COLUMN chat_id BIGINT;
+UPDATE orders SET chat_id = user_id;
+ALTER TABLE orders ALTER COLUMN chat_id SET NOT NULL;
+
+-- Members of a group, that have recently used the bot there. A round is ordered for them.
+CREATE TABLE group_members (
+  chat_id BIGINT NOT NULL,
+  user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+  last_seen_at TIMESTAMPTZ NOT NULL default CURRENT_TIMESTAMP,
+  PRIMARY KEY (chat_id, user_id)
+);
@@ -0,0 +1,7 @@
+-- This file should undo anything in `up.sql`
+UPDATE payments SET status = 'refunded' WHERE status = 'refund_pending';
+ALTER TABLE payments DROP CONSTRAINT payments_status_check;
+ALTER TABLE payments ADD CONSTRAINT payments_status_check
+  CHECK (status IN ('received', 'transfer_pending', 'transferred', 'transfer_failed', 'refunded'));
+
+DROP TABLE refunds;
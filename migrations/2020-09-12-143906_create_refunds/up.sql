@@ -0,0 +1,17 @@
+-- Your SQL goes here
+-- A payment is refunded completely, so there is at most one refund per payment
+CREATE TABLE refunds (
+  id SERIAL PRIMARY KEY,
+  payment_id INTEGER NOT NULL UNIQUE REFERENCES payments(id),
+  stripe_refund_id VARCHAR NOT NULL,
+  amount MONEY NOT NULL,
+  reason VARCHAR,
+  -- True if the money had already been transfered to the pub and has been taken back
+  transfer_reversed BOOLEAN NOT NULL default FALSE,
+  refunded_at TIMESTAMPTZ NOT NULL default CURRENT_TIMESTAMP
+);
+
+-- A refund is claimed with refund_pending before Stripe is called
+ALTER TABLE payments DROP CONSTRAINT payments_status_check;
+ALTER TABLE payments ADD CONSTRAINT payments_status_check
+  CHECK (status IN ('received', 'transfer_pending', 'transferred', 'transfer_failed', 'refund_pending', 'refunded'));
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP TABLE payment_fees;
+ALTER TABLE payments DROP COLUMN fee;
+ALTER TABLE payments DROP COLUMN estimated_fee;
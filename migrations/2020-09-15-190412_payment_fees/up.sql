@@ -0,0 +1,14 @@
+-- Your SQL goes here
+-- estimated_fee has been shown on the invoice, fee is what Stripe has actually withheld
+ALTER TABLE payments ADD COLUMN estimated_fee MONEY;
+ALTER TABLE payments ADD COLUMN fee MONEY;
+
+-- The parts of the fee as reported by Stripe (fee_details of the balance transaction)
+CREATE TABLE payment_fees (
+  id SERIAL PRIMARY KEY,
+  payment_id INTEGER NOT NULL REFERENCES payments(id),
+  amount MONEY NOT NULL,
+  currency VARCHAR NOT NULL,
+  description VARCHAR NOT NULL,
+  fee_type VARCHAR NOT NULL
+);
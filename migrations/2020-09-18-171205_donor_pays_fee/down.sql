@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE payments DROP COLUMN donor_paid_fee;
+ALTER TABLE users DROP COLUMN donor_pays_fee;
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+-- If true, the fee is added to the invoice, so the pub receives the whole tab
+ALTER TABLE users ADD COLUMN donor_pays_fee BOOLEAN NOT NULL default FALSE;
+ALTER TABLE payments ADD COLUMN donor_paid_fee BOOLEAN NOT NULL default FALSE;
@@ -0,0 +1,21 @@
+-- Your SQL goes here
+-- The row without user_id is the global default, the row of a user overrides it.
+-- Every limit is overridden separately, a limit that is NULL everywhere is not enforced.
+CREATE TABLE spending_limits (
+  id SERIAL PRIMARY KEY,
+  user_id INTEGER UNIQUE REFERENCES users(id) ON DELETE CASCADE,
+  -- Open orders of the user in all chats
+  max_tab MONEY,
+  -- The tab payed with one invoice (a Stripe fee the donor pays on top does not count)
+  max_invoice MONEY,
+  -- Payments within the last day, 7 days and 30 days (without a fee the donor payed on top)
+  max_daily MONEY,
+  max_weekly MONEY,
+  max_monthly MONEY
+);
+-- There can only be one global default
+CREATE UNIQUE INDEX spending_limits_default ON spending_limits ((user_id IS NULL)) WHERE user_id IS NULL;
+
+-- The former fixed limit of a tab. As an invoice never pays more than the open orders,
+-- the invoice limit only matters, if the limit of the tab is raised (or removed) for a user.
+INSERT INTO spending_limits (max_tab, max_invoice) VALUES (CAST(14.99 AS MONEY), CAST(20.00 AS MONEY));
@@ -1,11 +1,11 @@
 // To choose DB first set ROCKET_ENV (in fish: set -x ROCKET_ENV dev)
 // DBs are configured in rocekt.toml for dev-environment
 //
-// To use http tunnel pass use flag "url" and the tunnel url (cargo run url https://tunnelurl.com)
-//
-// To swap bot and use remoteDeckelTest_bot pass "test" flag
-// - (cargo run test)
-// - or with url (cargo run url https://tunnerlurl.com test)
+// All other settings are read from deckel.toml (see deckel.toml.example)
+// The environment (dev, test or prod) is chosen with DECKEL_ENV (in fish: set -x DECKEL_ENV test)
+// - test uses the remoteDeckelTest_bot
+// - to use a http tunnel set hosting_url (or DECKEL_HOSTING_URL=https://tunnelurl.com)
+// - to receive updates without a public url (no webhook) set polling = true
 //
 #![feature(proc_macro_hygiene, decl_macro)]
 
@@ -14,24 +14,39 @@ extern crate rocket;
 #[macro_use]
 extern crate diesel_migrations;
 
-use bot_lib::bot_context::{BotContext, MAX_DAMAGE_ALLOWED};
-use bot_lib::bot_types::{Keyboards, Payload, RequestType};
-use bot_lib::payments::{self, money_in_eur};
-use bot_lib::telegram_types::{self, PreCheckoutQueryResponseMessage, ResponseMessage, Update};
-use bot_lib::*;
-use bot_lib::{db, models};
+use bot_lib::config::{Config, Environment};
+use bot_lib::db;
+use bot_lib::error::{BotError, BotResult};
+use bot_lib::i18n;
+use bot_lib::refunds;
+use bot_lib::reports;
+use bot_lib::stripe_events;
+use bot_lib::stripe_types::Event;
+use bot_lib::telegram_client::bot_method_url;
+use bot_lib::telegram_types::Update;
+use bot_lib::transfer_worker;
+use bot_lib::update_handler;
+use bot_lib::update_poller;
+use chrono::Utc;
 use dotenv::dotenv;
 use reqwest;
 use rocket::fairing::AdHoc;
-use rocket::http::{RawStr, Status};
+use rocket::http::Status;
 use rocket::request::{self, FromRequest, Request};
 use rocket::response::content;
-use rocket::{post, routes, Outcome, Rocket};
+use rocket::{post, routes, Data, Outcome, Rocket, State};
+use rocket_contrib::databases::database_config;
 use rocket_contrib::json::Json;
+use serde::Deserialize;
+use std::io::Read;
+use subtle::ConstantTimeEq;
 
 embed_migrations!();
 
-static HOUR: i64 = 3600;
+// Fixed route of the Telegram webhook, it is protected by the secret token
+static WEBHOOK_PATH: &str = "telegram";
+// Stripe events are small, anything bigger than this is not read
+static STRIPE_EVENT_SIZE_LIMIT: u64 = 64 * 1024;
 
 /// CleverCloud sends continiuous (almost every minute) monitoring-GET-requests to the app-route.
 /// Those cause a rocket-error if no GET("/") is configured.
@@ -64,161 +79,197 @@ fn handle_get(_metrics_request: MonitoringRequest) -> String {
     "Received Monitoring GET-Request from clever cloud".to_string()
 }
 
-#[post("/<bot_endpoint>", format = "json", data = "<update>")]
-fn handle_update(
-    bot_endpoint: &RawStr,
-    conn: db::UserDbConn,
-    update: Json<Update>,
-) -> content::Json<String> {
-    if !is_request_legit(bot_endpoint) {
-        return content::Json("{ 'response': 403 }".to_string());
-    }
+// An empty token accepts nothing. The comparison takes the same time no matter
+// how many characters are right, so the token can not be guessed one by one.
+fn is_valid_token(token: &str, expected: &str) -> bool {
+    !expected.is_empty() && bool::from(token.as_bytes().ct_eq(expected.as_bytes()))
+}
 
-    let json_response_str = match (update.pre_checkout_query.as_ref(), update.message.as_ref()) {
-        (Some(query), None) => create_answer_pre_checkout_response(query),
-        (None, Some(message)) => match message.successful_payment.as_ref() {
-            None => create_response_message(message, conn),
-            Some(successful_payment) => {
-                match payments::pay(&successful_payment, conn) {
-                    Ok(_) => {}
-                    Err(e) => eprintln!("Could not process payment. Err: {}", e),
-                }
-                create_successful_payment_response(&successful_payment.get_payload())
+/// Telegram sends the secret_token, that has been registered with setWebhook,
+/// in the X-Telegram-Bot-Api-Secret-Token header of every update.
+/// Updates without the header, or with a wrong token, are rejected.
+struct TelegramSecretToken;
+
+impl<'a, 'r> FromRequest<'a, 'r> for TelegramSecretToken {
+    type Error = String;
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let config = match request.guard::<State<Config>>() {
+            Outcome::Success(config) => config,
+            _ => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    "Config is not managed by rocket".to_string(),
+                ))
             }
-        },
-        _ => panic!("No query or message?...TODO: http 500 response"),
-    };
+        };
+        match request.headers().get_one("X-Telegram-Bot-Api-Secret-Token") {
+            // Without a secret (e.g. in poll-mode) no update is accepted via webhook
+            Some(token) if is_valid_token(token, &config.webhook_secret) => {
+                Outcome::Success(TelegramSecretToken)
+            }
+            Some(_) => {
+                eprintln!("Received update with invalid secret token");
+                Outcome::Failure((Status::Unauthorized, "Invalid secret token".to_string()))
+            }
+            None => Outcome::Failure((
+                Status::Unauthorized,
+                "X-Telegram-Bot-Api-Secret-Token header is missing".to_string(),
+            )),
+        }
+    }
+}
 
-    content::Json(json_response_str)
+#[post("/telegram", format = "json", data = "<update>")]
+fn handle_update(
+    _secret_token: TelegramSecretToken,
+    conn: db::UserDbConn,
+    config: State<Config>,
+    update: Json<Update>,
+) -> Result<content::Json<String>, BotError> {
+    Ok(content::Json(update_handler::handle_update(
+        &update, &conn, &config,
+    )?))
 }
 
-fn is_request_legit(attempted_route: &RawStr) -> bool {
-    let key = if is_test() { "API_KEY_TEST" } else { "API_KEY" };
-    let legit_route = std::env::var(key).unwrap();
-    if attempted_route != legit_route {
-        eprintln!("Bot was tried to be accessed on route {}.", attempted_route);
-        return false;
+/// Stripe signs every webhook event. The signature is sent in the Stripe-Signature header.
+/// Requests without this header are rejected right away.
+struct StripeSignature(String);
+
+impl<'a, 'r> FromRequest<'a, 'r> for StripeSignature {
+    type Error = String;
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        match request.headers().get_one("Stripe-Signature") {
+            Some(value) => Outcome::Success(StripeSignature(value.to_string())),
+            None => Outcome::Failure((
+                Status::BadRequest,
+                "Stripe-Signature header is missing".to_string(),
+            )),
+        }
     }
-    true
 }
 
-fn create_response_message(
-    incoming_message: &telegram_types::Message,
+#[post("/stripe", data = "<event>")]
+fn handle_stripe_event(
+    signature: StripeSignature,
     conn: db::UserDbConn,
-) -> String {
-    let telegram_user = match &incoming_message.from {
-        Some(user) => user,
-        None => panic!("message has no sender?...(from = None)"),
-    };
-    let current_user = match get_user_from_db(&telegram_user, &conn) {
-        Ok(user) => user,
-        Err(_) => {
-            let new_user = persist_new_user(&telegram_user, &conn);
-            println!(
-                "New user: {} with id: {} has been created",
-                new_user.name, new_user.id
-            );
-            new_user
-        }
-    };
-    let chat_id = incoming_message.chat.id;
-    let user_text = get_text_from_message(&incoming_message);
-    let timestamp = incoming_message.date as i64 + (HOUR * 2);
-    let mut bot_context = BotContext::new(current_user, conn, chat_id, user_text, timestamp);
-    let keyboards = Keyboards::init();
-    let request_type = bot_context.get_request_type(&incoming_message, &keyboards);
-
-    let response_message_json = match bot_context.handle_request(request_type, &keyboards) {
-        Ok(json) => json,
-        Err(e) => panic!("Request could not be handles. Error: {}", e),
-    };
-    response_message_json
-}
+    config: State<Config>,
+    event: Data,
+) -> Status {
+    // The signature is calculated over the raw body, so it must be read as is
+    let mut payload = String::new();
+    if let Err(e) = event
+        .open()
+        .take(STRIPE_EVENT_SIZE_LIMIT)
+        .read_to_string(&mut payload)
+    {
+        eprintln!("Could not read Stripe event. Err: {}", e);
+        return Status::BadRequest;
+    }
 
-fn create_answer_pre_checkout_response(query: &telegram_types::PreCheckoutQuery) -> String {
-    let payload_json = serde_json::from_str(&query.invoice_payload);
-    // TODO: Do something more useful (maybe like persisting) query.payload
-    let payload: Payload = match payload_json {
-        Ok(payload) => payload,
-        Err(e) => panic!("Could not parse pre_checkout_query.payload. Error: {}", e),
-    };
-    let is_total_ok = payload.total < MAX_DAMAGE_ALLOWED;
-    let answer_query = PreCheckoutQueryResponseMessage::new(&query.id, is_total_ok);
-    let answer_query_json = serde_json::to_string(&answer_query).unwrap();
-    answer_query_json
-}
+    let secret = &config.stripe_webhook_secret;
+    if !stripe_events::is_signature_valid(&signature.0, &payload, secret, Utc::now().timestamp()) {
+        eprintln!("Received Stripe event with invalid signature");
+        return Status::Unauthorized;
+    }
 
-fn create_successful_payment_response(payload: &Payload) -> String {
-    let response_message = ResponseMessage {
-        method: "sendMessage".to_string(),
-        chat_id: payload.chat_id,
-        text: format!(
-            "🙏 Danke für deine Spende 🙏\n💶 in Höhe von {:.2},-€ 💶\n🦸 Du bist ein Retter! 🦸",
-            money_in_eur(payload.total)
-        ),
-        reply_markup: Some(Keyboards::init().get_keyboard(RequestType::PayYes)),
+    let event: Event = match serde_json::from_str(&payload) {
+        Ok(event) => event,
+        Err(e) => {
+            eprintln!("Could not parse Stripe event. Err: {}", e);
+            return Status::BadRequest;
+        }
     };
-    serde_json::to_string(&response_message).unwrap()
+    match stripe_events::handle_event(event, &conn) {
+        Ok(()) => Status::Ok,
+        Err(e) => {
+            eprintln!("Could not handle Stripe event. Err: {}", e);
+            Status::UnprocessableEntity
+        }
+    }
 }
 
-fn get_user_from_db(
-    telegram_user: &telegram_types::User,
-    conn: &db::UserDbConn,
-) -> Result<models::User, diesel::result::Error> {
-    db::get_user_by_id(telegram_user.id, conn)
+/// Admin requests must send the admin_token of the config in the X-Deckel-Admin-Token header.
+/// Without a configured admin_token, all admin requests are rejected.
+struct AdminToken;
+
+impl<'a, 'r> FromRequest<'a, 'r> for AdminToken {
+    type Error = String;
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let config = match request.guard::<State<Config>>() {
+            Outcome::Success(config) => config,
+            _ => {
+                return Outcome::Failure((
+                    Status::InternalServerError,
+                    "Config is not managed by rocket".to_string(),
+                ))
+            }
+        };
+        match request.headers().get_one("X-Deckel-Admin-Token") {
+            Some(token) if is_valid_token(token, &config.admin_token) => {
+                Outcome::Success(AdminToken)
+            }
+            Some(_) => {
+                eprintln!("Received admin request with invalid token");
+                Outcome::Failure((Status::Unauthorized, "Invalid admin token".to_string()))
+            }
+            None => Outcome::Failure((
+                Status::Unauthorized,
+                "X-Deckel-Admin-Token header is missing".to_string(),
+            )),
+        }
+    }
 }
 
-fn persist_new_user(telegram_user: &telegram_types::User, conn: &db::UserDbConn) -> models::User {
-    let user_name = match telegram_user.username {
-        Some(ref username) => username,
-        None => "undefined",
-    };
-    let first_name = telegram_user.first_name.to_string();
-    let last_name = match telegram_user.last_name {
-        Some(ref last_name) => last_name,
-        None => "undefined",
-    };
-    let new_user = models::NewUser {
-        id: telegram_user.id,
-        name: &user_name,
-        first_name: &first_name,
-        last_name: last_name,
-    };
-    db::save_user(new_user, conn)
+#[derive(Deserialize)]
+struct RefundRequest {
+    payment_id: i32,
+    // Is kept with the refund, e.g. "payed twice"
+    reason: Option<String>,
 }
 
-fn get_text_from_message(telegram_message: &telegram_types::Message) -> String {
-    match &telegram_message.text {
-        Some(text) => text.to_string(),
-        None => "".to_string(),
-    }
+#[post("/admin/refunds", format = "json", data = "<request>")]
+fn handle_refund(
+    _admin_token: AdminToken,
+    conn: db::UserDbConn,
+    config: State<Config>,
+    request: Json<RefundRequest>,
+) -> Result<String, BotError> {
+    let refund = refunds::refund_payment(
+        request.payment_id,
+        request.reason.as_deref(),
+        &conn,
+        &config,
+    )?;
+    Ok(format!(
+        "Payment {} has been refunded with {} (transfer reversed: {})",
+        refund.payment_id, refund.stripe_refund_id, refund.transfer_reversed
+    ))
 }
 
-fn bot_method_url(method: &str, api_key: &str) -> String {
-    let telegram_base_url = "https://api.telegram.org/bot";
-    format!("{}{}/{}", telegram_base_url, api_key, method)
+#[get("/admin/reports/fees")]
+fn handle_fee_report(_admin_token: AdminToken, conn: db::UserDbConn) -> Result<String, BotError> {
+    reports::fee_report(&conn)
 }
 
-async fn set_webhook(bot_url: &str, api_key: &str) -> reqwest::Result<()> {
+async fn set_webhook(bot_url: &str, config: &Config) -> reqwest::Result<()> {
     // Register update webHook with Telegram
-    let telegram_set_webhook_url = format!(
-        "{}?url={}/{}",
-        bot_method_url("setWebhook", api_key),
-        bot_url,
-        api_key
-    );
-    println!(
-        "Tries to register webHook with GET to: {}",
-        telegram_set_webhook_url
-    );
+    // The url (and the api_key in it) is not logged, only the public webhook-url
+    let webhook_url = format!("{}/{}", bot_url, WEBHOOK_PATH);
+    println!("Tries to register webHook: {}", webhook_url);
 
-    let webhook_response = reqwest::get(&telegram_set_webhook_url)
+    let webhook_response = reqwest::Client::new()
+        .post(&bot_method_url("setWebhook", &config.api_key))
+        .form(&[
+            ("url", webhook_url.as_str()),
+            ("secret_token", config.webhook_secret.as_str()),
+        ])
+        .send()
         .await?
         .text()
         .await?;
     println!("SetWebhook-Response: {:?}", webhook_response);
 
-    let webhook_info = reqwest::get(&bot_method_url("getWebhookInfo", api_key))
+    let webhook_info = reqwest::get(&bot_method_url("getWebhookInfo", &config.api_key))
         .await?
         .text()
         .await?;
@@ -226,14 +277,68 @@ async fn set_webhook(bot_url: &str, api_key: &str) -> reqwest::Result<()> {
     Ok(())
 }
 
-fn launch_rocket() {
+fn launch_rocket(config: Config) {
     rocket::ignite()
-        .mount("/", routes![handle_update, handle_get])
+        .manage(config)
+        .mount(
+            "/",
+            routes![
+                handle_update,
+                handle_get,
+                handle_stripe_event,
+                handle_refund,
+                handle_fee_report
+            ],
+        )
         .attach(db::UserDbConn::fairing())
         .attach(AdHoc::on_attach("Database Migration", run_db_migrations))
+        .attach(AdHoc::on_attach("Active Pubs", check_active_pubs))
+        .attach(AdHoc::on_attach("Transfer Worker", start_transfer_worker))
+        .attach(AdHoc::on_attach("Update Poller", start_update_poller))
         .launch();
 }
 
+// The worker connects on its own, so it only needs the url of the configured database
+fn start_transfer_worker(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let config = get_config(&rocket);
+    match database_config("remote_deckel", rocket.config()) {
+        Ok(db_config) => {
+            transfer_worker::start(db_config.url.to_string(), config);
+        }
+        Err(e) => eprintln!("Transfer worker could not be started: {:?}", e),
+    }
+    Ok(rocket)
+}
+
+// Only started in poll-mode. The webhook-route stays mounted, but Telegram does not use it.
+fn start_update_poller(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let config = get_config(&rocket);
+    if !config.polling {
+        return Ok(rocket);
+    }
+    match database_config("remote_deckel", rocket.config()) {
+        Ok(db_config) => match update_poller::start(db_config.url.to_string(), config) {
+            Ok(_) => Ok(rocket),
+            Err(e) => {
+                eprintln!("Update poller could not be started: {}", e);
+                Err(rocket)
+            }
+        },
+        Err(e) => {
+            eprintln!("Update poller could not be started: {:?}", e);
+            Err(rocket)
+        }
+    }
+}
+
+// The config is managed before any fairing is attached
+fn get_config(rocket: &Rocket) -> Config {
+    rocket
+        .state::<Config>()
+        .expect("Config is not managed by rocket")
+        .clone()
+}
+
 // see: https://stackoverflow.com/questions/61047355/how-to-run-diesel-migration-with-rocket-in-production
 // and: https://docs.rs/crate/diesel_migrations/1.4.0
 fn run_db_migrations(rocket: Rocket) -> Result<Rocket, Rocket> {
@@ -248,24 +353,73 @@ fn run_db_migrations(rocket: Rocket) -> Result<Rocket, Rocket> {
     }
 }
 
+// Without an active pub nobody can pay. The seeded pub is inactive until its Stripe account is set:
+// UPDATE pubs SET stripe_account = '<acct_...>', active = true WHERE name = '<pub>';
+fn check_active_pubs(rocket: Rocket) -> Result<Rocket, Rocket> {
+    let conn =
+        db::UserDbConn::get_one(&rocket).expect("Could not establish rocket with DB connection");
+    let active_pubs = match db::get_active_pubs(&*conn) {
+        Ok(active_pubs) => active_pubs,
+        Err(e) => {
+            eprintln!("Could not load the active pubs: {}", e);
+            return Err(rocket);
+        }
+    };
+    if !active_pubs.is_empty() {
+        return Ok(rocket);
+    }
+    let config = get_config(&rocket);
+    eprintln!(
+        "!!! There is no active pub, donations are not possible. Activate a pub with its Stripe account (acct_...) !!!"
+    );
+    match config.environment {
+        Environment::Prod => Err(rocket),
+        _ => Ok(rocket),
+    }
+}
+
 #[tokio::main]
-async fn main() -> reqwest::Result<()> {
+async fn main() -> BotResult<()> {
     // Set env-variables (port and postgres-db)
     dotenv().ok();
 
-    let api_key = match is_test() {
-        true => std::env::var("API_KEY_TEST"),
-        false => std::env::var("API_KEY"),
-    };
-    let hosting_url = match get_ngrok_url() {
-        Some(url) => Ok(url),
-        None => std::env::var("HOSTING_URL"),
-    };
-    match (&hosting_url, &api_key) {
-        (Ok(url), Ok(key)) => set_webhook(url, key).await?,
-        _ => eprintln!("Webhook setup disabled"),
+    // Invalid settings stop the bot right away, not when they are used the first time
+    let config = Config::load()?;
+    i18n::validate()?;
+    println!("Starting in environment: {}", config.environment);
+
+    match &config.hosting_url {
+        _ if config.polling => println!("Webhook setup skipped, updates are polled"),
+        Some(url) => set_webhook(url, &config)
+            .await
+            .map_err(|e| BotError::Telegram(e.to_string()))?,
+        None => eprintln!("Webhook setup disabled"),
     }
 
-    launch_rocket();
+    launch_rocket(config);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_the_expected_token() {
+        assert!(is_valid_token("s3cr3t-token", "s3cr3t-token"));
+    }
+
+    #[test]
+    fn rejects_other_tokens() {
+        assert!(!is_valid_token("s3cr3t-tokem", "s3cr3t-token"));
+        assert!(!is_valid_token("s3cr3t", "s3cr3t-token"));
+        assert!(!is_valid_token("s3cr3t-token-and-more", "s3cr3t-token"));
+        assert!(!is_valid_token("", "s3cr3t-token"));
+    }
+
+    #[test]
+    fn rejects_everything_without_an_expected_token() {
+        assert!(!is_valid_token("", ""));
+        assert!(!is_valid_token("anything", ""));
+    }
+}
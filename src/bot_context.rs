@@ -1,247 +1,760 @@
-use crate::bot_types::{Keyboards, Payload, RequestType};
-use crate::models::UpdateUser;
+use crate::bot_types::{Keyboards, Payload, RequestType, TabEntry};
+use crate::config::Config;
+use crate::error::{BotError, BotResult};
+use crate::i18n::{Language, Texts};
+use crate::limits::{LimitExceeded, Limits};
+use crate::models::{ConversationState, GroupMember, NewConversation, UpdateUser};
+use crate::payments::*;
 use crate::telegram_types::LabeledPrice as lp;
 use crate::telegram_types::{self, *};
-use crate::{db, messages, models};
+use crate::{conversation, db, models};
 use chrono::{DateTime, TimeZone, Utc};
+use chrono_tz::{Europe, Tz};
 use diesel::pg::types::money::PgMoney;
-use crate::payments::*;
+use rocket_contrib::databases::diesel::PgConnection;
+
+// Used if the timezone of the user or pub is not a valid name of the tz database
+static DEFAULT_TIMEZONE: Tz = Europe::Berlin;
 
+// Orders older than this (in seconds) can not be undone anymore
+pub static UNDO_TIME_LIMIT: i64 = 5 * 60;
 
-// Everything higher than this cents value is forbidden
-// to prevent the user from unintentionally high donations
-pub static MAX_DAMAGE_ALLOWED: i64 = 1499;
+// Members, that have not used the bot in a group for this long (in seconds), are not present anymore
+pub static PRESENCE_TIME_LIMIT: i64 = 3 * 60 * 60;
 
-pub struct BotContext {
+// Bounds of a drink price in cents, the maximum should stay below the tab limit (see limits)
+pub static MIN_PRICE: i64 = 10;
+pub static MAX_PRICE: i64 = 1000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum PriceError {
+    NotAPrice,
+    TooLow,
+    TooHigh,
+}
+
+pub enum UndoResult {
+    Undone(models::Drink),
+    TooLate(models::Drink),
+    NothingToUndo,
+}
+
+pub struct BotContext<'a> {
     current_user: models::User,
-    conn: db::UserDbConn,
-    chat_id: i32,
+    conn: &'a PgConnection,
+    config: &'a Config,
+    // Every chat has its own tab
+    chat_id: i64,
+    in_group: bool,
+    // The message of the pressed inline-button, it is edited instead of sending a new one
+    message_id: Option<i32>,
     request_message: String,
-    // Currently not used
-    _date: DateTime<Utc>,
+    date: DateTime<Utc>,
+    // In the language of the current user
+    texts: Texts,
 }
 
-impl BotContext {
+impl<'a> BotContext<'a> {
     pub fn new(
         current_user: models::User,
-        conn: db::UserDbConn,
-        chat_id: i32,
+        conn: &'a PgConnection,
+        config: &'a Config,
+        chat: &telegram_types::Chat,
+        message_id: Option<i32>,
         request_message: String,
         timestamp: i64,
     ) -> Self {
+        let texts = Texts::new(Language::of_user(&current_user));
         BotContext {
             current_user,
             conn,
-            chat_id,
+            config,
+            chat_id: chat.id,
+            in_group: chat.is_group(),
+            message_id,
             request_message: request_message.to_string(),
-            _date: Utc.timestamp(timestamp, 0),
+            date: Utc.timestamp(timestamp, 0),
+            texts,
         }
     }
 
-    pub fn handle_request(
-        &mut self,
-        request_type: RequestType,
-        keyboards: &Keyboards,
-    ) -> serde_json::Result<String> {
+    pub fn handle_request(&mut self, request_type: RequestType) -> BotResult<String> {
+        let request_type = match request_type {
+            RequestType::Round(_) | RequestType::JoinRound if !self.in_group => {
+                RequestType::Unknown
+            }
+            request_type => request_type,
+        };
+        if self.in_group {
+            self.mark_present()?;
+        }
+        let state = self.conversation_state()?;
+        let request_type = match conversation::accepts(state, request_type) {
+            true => request_type,
+            false => {
+                println!(
+                    "Rejected {} of user with id: {} in state {}",
+                    request_type.action_id(),
+                    self.current_user.id,
+                    state
+                );
+                RequestType::Expired
+            }
+        };
+        // Usually the keyboard that belongs to the request
+        let mut keyboard_type = request_type;
+        let mut pay_blocked = false;
         let response_text = match request_type {
-            RequestType::Start => messages::WELCOME_MESSAGE.to_string(),
-            RequestType::Terms => messages::TERMS.to_string(),
-            RequestType::Order => {
-                match self.order_drink() {
-                    Some(new_drink_count) => format!("👍 Ich schreib's auf deinen Deckel.\n🍻 Bisher sind es {} Biers", new_drink_count),
-                    None => format!("🤔 Du hast schon {:.2}€ auf dem Deckel.\n💰Der maximal erlaubte Schaden beträgt {:.2}€.\n💳 Ich muss leider erst abrechnen bevor du mehr bestellen kannst.", money_in_eur(self.get_damage()), money_in_eur(MAX_DAMAGE_ALLOWED)),
-                } 
-
+            RequestType::Start => self.texts.get("welcome"),
+            // The terms must be shown even without an active pub
+            RequestType::Terms => {
+                let pub_name = match self.find_pub()? {
+                    Some(chosen_pub) => chosen_pub.name,
+                    None => self.texts.get("terms_no_pub"),
+                };
+                self.texts.format("terms", &[("pub", &pub_name)])
             }
-            RequestType::ShowDamage => format!(
-                "Du hast bisher {} Biers 🍻 bestellt.\nBeim aktuellen Preis 💶 von {:.2}€ beträgt dein derzeitiger Deckel insgesamt {:.2}€.",
-                self.current_user.drink_count,
-                money_in_eur(self.current_user.price.0),
-                money_in_eur(self.get_damage())
-            ),
-            RequestType::BillPlease => format!(
-                "💶 Dein derzeitiger Schaden beträgt {:.2}€. 💶\nMöchtest du wirklich zahlen?",
-                money_in_eur(self.get_damage())
+            RequestType::Order(drink_id) => match self.order_drink(drink_id)? {
+                Ok((drink, new_drink_count)) => self.texts.format(
+                    "order_added",
+                    &[("emoji", &drink.emoji), ("count", &new_drink_count), ("drink", &self.texts.drink_name(&drink))],
+                ),
+                Err(exceeded) => self.texts.format("order_blocked", &[("reason", &exceeded.text(&self.texts))]),
+            },
+            RequestType::Round(drink_id) => match self.order_round(drink_id)? {
+                Ok((drink, members)) => self.texts.format(
+                    "round_ordered",
+                    &[
+                        ("name", &self.current_user.first_name),
+                        ("emoji", &drink.emoji),
+                        ("drink", &self.texts.drink_name(&drink)),
+                        ("count", &members.len()),
+                        ("members", &member_names(&members)),
+                        ("damage", &self.texts.money(self.get_damage()?)),
+                    ],
+                ),
+                Err(exceeded) => self.texts.format("order_blocked", &[("reason", &exceeded.text(&self.texts))]),
+            },
+            RequestType::JoinRound => self.texts.format(
+                "round_joined",
+                &[
+                    ("name", &self.current_user.first_name),
+                    ("members", &member_names(&self.get_present_members()?)),
+                ],
             ),
-            RequestType::PayNo => "Ok, dann lass uns lieber weiter trinken.".to_string(),
-            RequestType::DeletePlease => {
-                "Möchtest du deine Userdaten wirklich löschen?".to_string()
+            RequestType::UndoOrder => match self.undo_last_order()? {
+                UndoResult::Undone(drink) => self.texts.format(
+                    "undo_done",
+                    &[
+                        ("drink", &self.texts.drink_name(&drink)),
+                        ("emoji", &drink.emoji),
+                        ("count", &db::count_open_orders_of_drink(self.current_user.id, drink.id, self.chat_id, self.conn)?),
+                        ("damage", &self.texts.money(self.get_damage()?)),
+                    ],
+                ),
+                UndoResult::TooLate(drink) => self.texts.format(
+                    "undo_too_late",
+                    &[("emoji", &drink.emoji), ("drink", &self.texts.drink_name(&drink)), ("minutes", &(UNDO_TIME_LIMIT / 60))],
+                ),
+                UndoResult::NothingToUndo => self.texts.get("undo_nothing"),
+            },
+            // In a group everybody sees, who owes what
+            RequestType::ShowDamage if self.in_group => self.group_tab()?,
+            RequestType::ShowDamage => {
+                let tab = self.get_tab()?;
+                match tab.is_empty() {
+                    true => self.texts.get("tab_empty"),
+                    false => self.texts.format(
+                        "tab",
+                        &[
+                            ("breakdown", &self.tab_breakdown(&tab)),
+                            ("damage", &self.texts.money(self.get_damage()?)),
+                        ],
+                    ),
+                }
+            }
+            RequestType::BillPlease => {
+                self.texts.format("bill_please", &[("damage", &self.texts.money(self.get_damage()?))])
             }
-            RequestType::DeleteNo => "Ok, deine Daten wurden nicht gelöscht.".to_string(),
+            RequestType::PayNo => self.texts.get("pay_no"),
+            RequestType::DeletePlease => self.texts.get("delete_please"),
+            RequestType::DeleteNo => self.texts.get("delete_no"),
             RequestType::DeleteYes => {
-                let deleted_user = self.delete_user();
-                // TODO: delete returns count not deleted_user
-                println!("User: {} with id: {} has been deleted", deleted_user, deleted_user);
-                "No problemo. Ich habe deine Daten gelöscht.".to_string()
+                self.delete_user()?;
+                println!("User: {} with id: {} has been deleted", self.current_user.name, self.current_user.id);
+                self.texts.get("delete_yes")
             }
             RequestType::Steal => {
-                self.erase_drinks();
-                "Ich habe deinen Deckel unauffällig zerrissen.".to_string()
+                self.erase_drinks()?;
+                self.texts.get("steal")
             }
-            RequestType::Options => "Was kann ich für dich tun?".to_string(),
-            RequestType::ChangePrice => "Wähle einen neuen Getränkepreis.".to_string(),
-            RequestType::NewPrice => {
-                let new_price = self.convert_price();
-                match self.update_price(new_price) {
-                    Some(price) => {
-                        format!(
-                            "Alles klar, jedes Getränk kostet jetzt {:.2}€",
-                            money_in_eur(price))
-                    },
-                    None => format!("Sorry, aber diese Erhöhung würde den zulässigen Schaden von {:.2}€ übersteigen.\nBitte zahle erst oder wähle einen anderen Preis.", money_in_eur(MAX_DAMAGE_ALLOWED)),
+            RequestType::Options => self.texts.get("options"),
+            RequestType::ChangePrice => self.texts.get("change_price"),
+            RequestType::ChangePub => match self.find_pub()? {
+                Some(chosen_pub) => self.texts.format("change_pub", &[("pub", &chosen_pub.name)]),
+                None => self.texts.get("no_pub"),
+            },
+            RequestType::NewPub(pub_id) => match self.update_pub(pub_id)? {
+                Some(chosen_pub) => self.texts.format("new_pub", &[("pub", &chosen_pub.name)]),
+                None => self.texts.get("new_pub_unavailable"),
+            },
+            RequestType::NewPrice(_) | RequestType::EnteredPrice => {
+                let new_price = match request_type {
+                    RequestType::NewPrice(new_price) => validate_price(new_price),
+                    _ => parse_price(&self.request_message),
+                };
+                match new_price {
+                    Ok(new_price) => {
+                        let price = self.update_price(new_price)?;
+                        self.texts.format("new_price", &[("price", &self.texts.money(price))])
+                    }
+                    Err(e) => {
+                        // The user can try again or choose one of the prices
+                        keyboard_type = RequestType::ChangePrice;
+                        self.price_error_text(e)
+                    }
+                }
+            }
+            RequestType::ChangeLanguage => self.texts.get("change_language"),
+            // Answered (and labeled) in the new language right away
+            RequestType::NewLanguage(language) => {
+                self.update_language(language)?;
+                self.texts.get("new_language")
+            }
+            RequestType::ChangeFee => match self.current_user.donor_pays_fee {
+                true => self.texts.get("change_fee_donor"),
+                false => self.texts.get("change_fee_pub"),
+            },
+            RequestType::NewFee(donor_pays_fee) => {
+                self.update_donor_pays_fee(donor_pays_fee)?;
+                match donor_pays_fee {
+                    true => self.texts.get("new_fee_donor"),
+                    false => self.texts.get("new_fee_pub"),
                 }
             }
             RequestType::ShowLast => {
                 let last_paid_amount = self.current_user.last_total.0;
-                match last_paid_amount {
-                    0 => "Du hast bisher noch nicht gespendet.".to_string(),
-                    _ => format!("Deine letzte Spende war am {:?} und betrug {:.2}€.",
-                        self.get_last_paid_as_date(), money_in_eur(self.current_user.last_total.0)),
+                let last_fee = db::get_last_payment_of_user(self.current_user.id, self.conn)?
+                    .map(|payment| payment.donor_fee())
+                    .unwrap_or(0);
+                match (last_paid_amount, last_fee) {
+                    (0, _) => self.texts.get("show_last_none"),
+                    (_, 0) => self.texts.format(
+                        "show_last",
+                        &[
+                            ("date", &self.get_last_paid_as_date()?),
+                            ("amount", &self.texts.money(last_paid_amount)),
+                        ],
+                    ),
+                    _ => self.texts.format(
+                        "show_last_with_fee",
+                        &[
+                            ("date", &self.get_last_paid_as_date()?),
+                            ("amount", &self.texts.money(last_paid_amount)),
+                            ("fee", &self.texts.money(last_fee)),
+                        ],
+                    ),
+                }
+            }
+            RequestType::ShowTotal => {
+                let total = self.texts.money(self.current_user.total.0);
+                match self.get_total_donor_fees()? {
+                    0 => self.texts.format("show_total", &[("amount", &total)]),
+                    fees => self.texts.format(
+                        "show_total_with_fee",
+                        &[("amount", &total), ("fee", &self.texts.money(fees))],
+                    ),
                 }
             }
-            RequestType::ShowTotal => format!(
-                "Insgesamt hast du {:.2}€ gespendet.",
-                money_in_eur(self.current_user.total.0)
-            ),
             RequestType::ShowTotalAll => {
-                let total_all = self.get_total_all();
+                let total_all = self.get_total_all()?;
                 match total_all {
-                    0 => "Bisher wurde noch nicht gespendet".to_string(),
-                    _ => format!("Zusammen haben wir bisher {:.2}€ gespendet.", money_in_eur(total_all)),
+                    0 => self.texts.get("show_total_all_none"),
+                    _ => self.texts.format("show_total_all", &[("amount", &self.texts.money(total_all))]),
                 }
             }
-            RequestType::Unknown => {
-                "🤷 Ehm, sorry darauf weiß ich grade keine Antwort...".to_string()
+            RequestType::Expired => self.texts.get("expired"),
+            RequestType::Unknown => self.texts.get("unknown"),
+            // Answered with the invoice, unless a limit is exceeded by now (e.g. by another payment)
+            RequestType::PayYes if self.find_pub()?.is_none() => {
+                pay_blocked = true;
+                self.texts.get("no_pub")
             }
-            RequestType::PayYes => "IGNORED".to_string(),
+            RequestType::PayYes => match self.check_invoice_limits()? {
+                Some(exceeded) => {
+                    pay_blocked = true;
+                    self.texts.format("pay_blocked", &[("reason", &exceeded.text(&self.texts))])
+                }
+                None => "IGNORED".to_string(),
+            },
         };
 
+        // The conversation of a deleted user has been deleted with it
+        if request_type != RequestType::DeleteYes {
+            self.update_conversation_state(state, conversation::next_state(keyboard_type))?;
+        }
+
         match request_type {
-            RequestType::PayYes => serde_json::to_string(&self.new_invoice()),
+            RequestType::PayYes if !pay_blocked => Ok(serde_json::to_string(&self.new_invoice()?)?),
             _ => {
                 let method = "sendMessage".to_string();
+                // Everyone in a group sees the answer, so it tells whom it is for
+                let response_text = match self.in_group {
+                    true => self.texts.format(
+                        "group_reply",
+                        &[("name", &self.current_user.first_name), ("text", &response_text)],
+                    ),
+                    false => response_text,
+                };
                 let response_message = ResponseMessage::new(method, self.chat_id, response_text);
-                let keyboard = keyboards.get_keyboard(request_type);
-                let response_message = response_message.keyboard(keyboard);
-                serde_json::to_string(&response_message)
+                let keyboards = Keyboards::init(
+                    &db::get_active_drinks(self.conn)?,
+                    &db::get_active_pubs(self.conn)?,
+                    &self.texts,
+                    self.in_group,
+                );
+                let keyboard = keyboards.get_keyboard(keyboard_type);
+                let response_message = match self.message_id {
+                    Some(message_id) => response_message.keyboard(keyboard).edit(message_id),
+                    // The buttons of the old reply keyboard send their label as text, which is unknown.
+                    // So the old keyboard is removed, the answer tells to start again with /start.
+                    None if request_type == RequestType::Unknown => response_message.remove_keyboard(),
+                    None => response_message.keyboard(keyboard),
+                };
+                Ok(serde_json::to_string(&response_message)?)
             }
         }
     }
 
-    pub fn order_drink(&mut self) -> Option<i16> {
-        let new_drink_count = self.current_user.drink_count + 1;
-        match (new_drink_count as i64 * self.current_user.price.0) < MAX_DAMAGE_ALLOWED {
+    pub fn order_drink(
+        &mut self,
+        drink_id: i32,
+    ) -> BotResult<Result<(models::Drink, i64), LimitExceeded>> {
+        let drink = db::get_drink_by_id(drink_id, self.conn)?;
+        let unit_price = self.unit_price(&drink);
+        match self.check_order_limits(unit_price)? {
+            None => {
+                let new_order = models::NewOrder {
+                    user_id: self.current_user.id,
+                    drink_id: drink.id,
+                    price: PgMoney(unit_price),
+                    ordered_at: self.date,
+                    chat_id: self.chat_id,
+                };
+                db::save_order(new_order, self.conn)?;
+                let drink_count = db::count_open_orders_of_drink(
+                    self.current_user.id,
+                    drink.id,
+                    self.chat_id,
+                    self.conn,
+                )?;
+                Ok(Ok((drink, drink_count)))
+            }
+            Some(exceeded) => Ok(Err(exceeded)),
+        }
+    }
+
+    // One drink for every present member of the group, all of them on the tab of the current user
+    pub fn order_round(
+        &mut self,
+        drink_id: i32,
+    ) -> BotResult<Result<(models::Drink, Vec<models::User>), LimitExceeded>> {
+        let drink = db::get_drink_by_id(drink_id, self.conn)?;
+        let unit_price = self.unit_price(&drink);
+        let members = self.get_present_members()?;
+        let round_price = unit_price * members.len() as i64;
+        match self.check_order_limits(round_price)? {
+            None => {
+                let new_orders: Vec<models::NewOrder> = members
+                    .iter()
+                    .map(|_| models::NewOrder {
+                        user_id: self.current_user.id,
+                        drink_id: drink.id,
+                        price: PgMoney(unit_price),
+                        ordered_at: self.date,
+                        chat_id: self.chat_id,
+                    })
+                    .collect();
+                db::save_orders(&new_orders, self.conn)?;
+                Ok(Ok((drink, members)))
+            }
+            Some(exceeded) => Ok(Err(exceeded)),
+        }
+    }
+
+    // The tab with the order must stay payable
+    fn check_order_limits(&self, order_price: i64) -> BotResult<Option<LimitExceeded>> {
+        let tab = self.get_damage()? + order_price;
+        let open_damage = db::get_open_damage_of_user(self.current_user.id, self.conn)? + order_price;
+        Limits::of_user(self.current_user.id, self.conn)?.check_order(
+            tab,
+            open_damage,
+            self.current_user.id,
+            self.date,
+            self.conn,
+        )
+    }
+
+    fn check_invoice_limits(&self) -> BotResult<Option<LimitExceeded>> {
+        Limits::of_user(self.current_user.id, self.conn)?.check_invoice(
+            self.get_damage()?,
+            self.current_user.id,
+            self.date,
+            self.conn,
+        )
+    }
+
+    // Either the fee is added on top, so the pub receives the whole tab, or it is taken from it.
+    // Returns the total of the invoice and the (estimated) fee.
+    fn invoice_amounts(&self, damage: i64) -> (i64, i64) {
+        match self.current_user.donor_pays_fee {
             true => {
-                let mut update_user = UpdateUser::from_user(&self.current_user);
-                update_user.drink_count = Some(new_drink_count);
-                db::update_user(self.current_user.id, &update_user, &self.conn);
-                Some(new_drink_count)
-            },
-            false => None,
+                let total = gross_up(damage, self.config);
+                (total, total - damage)
+            }
+            false => (damage, estimate_stripe_fee(damage, self.config)),
+        }
+    }
+
+    // Everyone, who uses the bot in a group, is present for a while
+    pub fn mark_present(&self) -> BotResult<()> {
+        let member = GroupMember {
+            chat_id: self.chat_id,
+            user_id: self.current_user.id,
+            last_seen_at: self.date,
+        };
+        db::save_group_member(&member, self.conn)?;
+        Ok(())
+    }
+
+    pub fn get_present_members(&self) -> BotResult<Vec<models::User>> {
+        let seen_since = self.date - chrono::Duration::seconds(PRESENCE_TIME_LIMIT);
+        Ok(db::get_present_members(
+            self.chat_id,
+            seen_since,
+            self.conn,
+        )?)
+    }
+
+    // The open orders of all members, every member pays their own share
+    fn group_tab(&self) -> BotResult<String> {
+        let open_orders = db::get_open_orders_of_chat(self.chat_id, self.conn)?;
+        if open_orders.is_empty() {
+            return Ok(self.texts.get("group_tab_empty"));
+        }
+        // The orders are sorted by member
+        let mut shares: Vec<(models::User, Vec<(models::Order, models::Drink)>)> = Vec::new();
+        for (order, drink, member) in open_orders {
+            match shares.last_mut() {
+                Some((last_member, orders)) if last_member.id == member.id => {
+                    orders.push((order, drink))
+                }
+                _ => shares.push((member, vec![(order, drink)])),
+            }
+        }
+
+        let mut total_damage = 0;
+        let breakdown = shares
+            .into_iter()
+            .map(|(member, orders)| {
+                let tab = TabEntry::from_orders(orders);
+                let damage: i64 = tab.iter().map(|entry| entry.damage).sum();
+                total_damage += damage;
+                let drinks = tab
+                    .iter()
+                    .map(|entry| {
+                        format!(
+                            "{}x {} {}",
+                            entry.drink_count,
+                            entry.drink.emoji,
+                            self.texts.drink_name(&entry.drink)
+                        )
+                    })
+                    .collect::<Vec<String>>()
+                    .join(", ");
+                self.texts.format(
+                    "group_tab_member",
+                    &[
+                        ("name", &member.first_name),
+                        ("drinks", &drinks),
+                        ("damage", &self.texts.money(damage)),
+                    ],
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n");
+        Ok(self.texts.format(
+            "group_tab",
+            &[
+                ("breakdown", &breakdown),
+                ("damage", &self.texts.money(total_damage)),
+            ],
+        ))
+    }
+
+    pub fn undo_last_order(&mut self) -> BotResult<UndoResult> {
+        let last_order = db::get_last_open_order(self.current_user.id, self.chat_id, self.conn)?;
+        let undo_result = match last_order {
+            Some((order, drink)) => {
+                let order_age = self.date - order.ordered_at;
+                match order_age.num_seconds() <= UNDO_TIME_LIMIT {
+                    true => {
+                        db::delete_order(&order, self.conn)?;
+                        UndoResult::Undone(drink)
+                    }
+                    false => UndoResult::TooLate(drink),
+                }
+            }
+            None => UndoResult::NothingToUndo,
+        };
+        Ok(undo_result)
+    }
+
+    pub fn get_tab(&self) -> BotResult<Vec<TabEntry>> {
+        let open_orders = db::get_open_orders(self.current_user.id, self.chat_id, self.conn)?;
+        Ok(TabEntry::from_orders(open_orders))
+    }
+
+    pub fn get_damage(&self) -> BotResult<i64> {
+        Ok(self.get_tab()?.iter().map(|entry| entry.damage).sum())
+    }
+
+    // The price the user has chosen wins over the price of the catalog
+    pub fn unit_price(&self, drink: &models::Drink) -> i64 {
+        match self.current_user.price {
+            Some(price) => price.0,
+            None => drink.price.0,
         }
     }
 
-    pub fn get_damage(&self) -> i64 {
-        let drinks = self.current_user.drink_count;
-        let price = self.current_user.price.0;
-        drinks as i64 * price
+    fn tab_breakdown(&self, tab: &[TabEntry]) -> String {
+        tab.iter()
+            .map(|entry| {
+                format!(
+                    "{} {}x {} = {}€",
+                    entry.drink.emoji,
+                    entry.drink_count,
+                    self.texts.drink_name(&entry.drink),
+                    self.texts.money(entry.damage)
+                )
+            })
+            .collect::<Vec<String>>()
+            .join("\n")
     }
 
-    pub fn convert_price(&self) -> i64 {
-        let new_price = self.request_message.replace("€", "").replace(",", "");
-        let new_price = if &new_price[..1] == "0" {
-            new_price[1..].to_string()
-        } else {
-            new_price
+    fn price_error_text(&self, error: PriceError) -> String {
+        match error {
+            PriceError::NotAPrice => self
+                .texts
+                .format("price_invalid", &[("input", &self.request_message)]),
+            PriceError::TooLow => self
+                .texts
+                .format("price_too_low", &[("min", &self.texts.money(MIN_PRICE))]),
+            PriceError::TooHigh => self
+                .texts
+                .format("price_too_high", &[("max", &self.texts.money(MAX_PRICE))]),
+        }
+    }
+
+    // Only orders after the change are affected. Earlier orders keep their price.
+    pub fn update_price(&mut self, new_price: i64) -> BotResult<i64> {
+        let update_user = UpdateUser {
+            price: Some(Some(PgMoney(new_price))),
+            ..Default::default()
         };
-        match new_price.parse::<i64>() {
-            Ok(price) => price,
-            Err(_) => unreachable!("Should not happen because, there is no RequestType::* for anything outside the button-options"),
+        db::update_user(self.current_user.id, &update_user, self.conn)?;
+        Ok(new_price)
+    }
+
+    // The state of the conversation with the user in this chat
+    pub fn conversation_state(&self) -> BotResult<ConversationState> {
+        let conversation = db::get_conversation(self.current_user.id, self.chat_id, self.conn)?;
+        Ok(conversation::current_state(conversation.as_ref(), self.date))
+    }
+
+    // Staying in a flow is saved as well, so its timeout starts again
+    pub fn update_conversation_state(
+        &self,
+        current: ConversationState,
+        next: ConversationState,
+    ) -> BotResult<()> {
+        if current != next || next != ConversationState::Idle {
+            let conversation = NewConversation {
+                user_id: self.current_user.id,
+                chat_id: self.chat_id,
+                state: next,
+                state_changed_at: self.date,
+            };
+            db::save_conversation(&conversation, self.conn)?;
         }
+        Ok(())
     }
 
-    pub fn update_price(&mut self, new_price: i64) -> Option<i64> {
-        let new_damage = self.current_user.drink_count as i64 * new_price;
-        match new_damage < MAX_DAMAGE_ALLOWED {
-            true => {
-                let mut update_user = UpdateUser::from_user(&self.current_user);
-                update_user.price = Some(PgMoney(new_price));
-                db::update_user(self.current_user.id, &update_user, &self.conn);
-                Some(new_price)
-            },
-            false => None,
+    // Following texts (and keyboards) are in the new language
+    pub fn update_language(&mut self, language: Language) -> BotResult<()> {
+        let update_user = UpdateUser {
+            language: Some(Some(language.code().to_string())),
+            ..Default::default()
+        };
+        db::update_user(self.current_user.id, &update_user, self.conn)?;
+        self.texts = Texts::new(language);
+        Ok(())
+    }
+
+    pub fn update_donor_pays_fee(&mut self, donor_pays_fee: bool) -> BotResult<()> {
+        let update_user = UpdateUser {
+            donor_pays_fee: Some(donor_pays_fee),
+            ..Default::default()
+        };
+        db::update_user(self.current_user.id, &update_user, self.conn)?;
+        self.current_user.donor_pays_fee = donor_pays_fee;
+        Ok(())
+    }
+
+    // The chosen pub, or the first active one if the user has not chosen (or it has been deactivated).
+    // None if there is no active pub at all.
+    pub fn find_pub(&self) -> BotResult<Option<models::Pub>> {
+        let chosen_pub = self
+            .current_user
+            .pub_id
+            .and_then(|pub_id| db::get_pub_by_id(pub_id, self.conn).ok())
+            .filter(|chosen_pub| chosen_pub.active);
+        match chosen_pub {
+            Some(chosen_pub) => Ok(Some(chosen_pub)),
+            None => Ok(db::get_active_pubs(self.conn)?.into_iter().next()),
+        }
+    }
+
+    pub fn get_pub(&self) -> BotResult<models::Pub> {
+        self.find_pub()?
+            .ok_or_else(|| BotError::Config("There is no active pub to donate to".to_string()))
+    }
+
+    pub fn update_pub(&mut self, pub_id: i32) -> BotResult<Option<models::Pub>> {
+        match db::get_pub_by_id(pub_id, self.conn) {
+            Ok(chosen_pub) if chosen_pub.active => {
+                let update_user = UpdateUser {
+                    pub_id: Some(Some(chosen_pub.id)),
+                    ..Default::default()
+                };
+                db::update_user(self.current_user.id, &update_user, self.conn)?;
+                Ok(Some(chosen_pub))
+            }
+            Ok(_) | Err(diesel::result::Error::NotFound) => Ok(None),
+            Err(e) => Err(e.into()),
         }
     }
 
-    pub fn erase_drinks(&mut self) {
-        let mut update_user = UpdateUser::from_user(&self.current_user);
-        update_user.drink_count = Some(0);
-        db::update_user(self.current_user.id, &update_user, &self.conn);
+    pub fn erase_drinks(&mut self) -> BotResult<usize> {
+        Ok(db::delete_open_orders(
+            self.current_user.id,
+            self.chat_id,
+            self.conn,
+        )?)
     }
 
-    pub fn get_last_paid_as_date(&self) -> String {
-        let date_time = Utc.timestamp(self.current_user.last_paid.0, 0);
-        date_time.format("%d.%m.%Y um %H:%Mh").to_string()
+    // Local time of the user, summer time included
+    pub fn get_last_paid_as_date(&self) -> BotResult<String> {
+        let date_time = self.current_user.last_paid.with_timezone(&self.timezone()?);
+        Ok(date_time.format(&self.texts.get("date_format")).to_string())
     }
 
-    pub fn get_total_all(&self) -> i64 {
-        let vec_of_totals = db::get_total_all(&self.conn);
-        vec_of_totals.iter().map(|money| money.0).sum()
+    // The timezone of the user, otherwise the one of the pub (or the default without an active pub)
+    pub fn timezone(&self) -> BotResult<Tz> {
+        let timezone = match &self.current_user.timezone {
+            Some(timezone) => timezone.to_string(),
+            None => match self.find_pub()? {
+                Some(chosen_pub) => chosen_pub.timezone,
+                None => return Ok(DEFAULT_TIMEZONE),
+            },
+        };
+        Ok(timezone.parse().unwrap_or_else(|e| {
+            eprintln!("Invalid timezone, falling back to {}. Err: {}", DEFAULT_TIMEZONE, e);
+            DEFAULT_TIMEZONE
+        }))
     }
 
-    pub fn delete_user(&self) -> usize {
-        db::delete_user(&self.current_user, &self.conn)
+    // All fees the user has payed on top of the donations
+    pub fn get_total_donor_fees(&self) -> BotResult<i64> {
+        let fee_paying_payments =
+            db::get_fee_paying_payments_of_user(self.current_user.id, self.conn)?;
+        Ok(fee_paying_payments.iter().map(|payment| payment.donor_fee()).sum())
     }
 
+    pub fn get_total_all(&self) -> BotResult<i64> {
+        let vec_of_totals = db::get_total_all(self.conn)?;
+        Ok(vec_of_totals.iter().map(|money| money.0).sum())
+    }
+
+    pub fn delete_user(&self) -> BotResult<usize> {
+        Ok(db::delete_user(&self.current_user, self.conn)?)
+    }
+
+    // Buttons are answered via callback_query, so only commands and answers are left to be typed
+    // None if the message is not meant for the bot, which happens only in groups:
+    // commands of other bots and chatter, that is not part of a conversation with the bot
     pub fn get_request_type(
         &self,
         message: &telegram_types::Message,
-        keyboards: &Keyboards,
-    ) -> RequestType {
-        let request_message = match &message.text {
-            Some(text) => text.to_string(),
-            None => match &message.sticker {
-                Some(sticker) => match &sticker.emoji {
-                    Some(emoji) => emoji.to_string(),
-                    None => return RequestType::Unknown,
-                },
-                None => return RequestType::Unknown,
-            },
+    ) -> BotResult<Option<RequestType>> {
+        let text = message.text.as_ref().map(|text| text.as_str()).unwrap_or("");
+        // In groups commands are sent as /start@remoteDeckel_bot
+        let text = match (text.starts_with('/'), text.find('@')) {
+            (true, Some(at)) => {
+                let (command, bot_username) = (&text[..at], &text[at + 1..]);
+                if !bot_username.eq_ignore_ascii_case(&self.config.bot_username) {
+                    return Ok(None);
+                }
+                command
+            }
+            _ => text,
         };
-        if request_message == "/start" {
-            return RequestType::Start;
-        }
-        if request_message == "/terms" {
-            return RequestType::Terms;
+        match text {
+            "/start" => Ok(Some(RequestType::Start)),
+            "/terms" => Ok(Some(RequestType::Terms)),
+            _ if text.starts_with('/') => Ok(Some(RequestType::Unknown)),
+            _ => match conversation::interpret_text(self.conversation_state()?, text, &self.texts) {
+                Some(request_type) => Ok(Some(request_type)),
+                None if self.in_group => Ok(None),
+                None => Ok(Some(RequestType::Unknown)),
+            },
         }
-        keyboards.get_request_type(&request_message)
     }
 
-    pub fn new_invoice(&self) -> InvoiceReplyMessage {
-        let provider_token =
-            std::env::var("PROVIDER_TOKEN").expect("Could not get provider_token from environment");
+    pub fn new_invoice(&self) -> BotResult<InvoiceReplyMessage> {
+        let chosen_pub = self.get_pub()?;
+        let provider_token = self.config.provider_token.to_string();
+        let damage = self.get_damage()?;
+        let donor_pays_fee = self.current_user.donor_pays_fee;
+        let (total, stripe_fee) = self.invoice_amounts(damage);
         let prices = vec![
-            lp::new("Gesamt-Netto", self.get_damage_net()),
-            lp::new("Stripe-Gebühr", calc_stripe_fee(self.get_damage())),
+            lp::new(&self.texts.get("invoice_net"), (total - stripe_fee) as i32),
+            lp::new(&self.texts.get("invoice_fee"), stripe_fee as i32),
         ];
-        let payload_result = serde_json::to_string(&Payload::new(
+        let payload = serde_json::to_string(&Payload::new(
             self.current_user.id,
             self.chat_id,
-            self.get_damage(),
+            total,
             self.current_user.total.0,
-        ));
-        let payload = match payload_result {
-            Ok(payload) => payload,
-            Err(e) => panic!("could not parse payload. Error: {}", e),
+            chosen_pub.id,
+            stripe_fee,
+            donor_pays_fee,
+        ))?;
+        let description_key = match donor_pays_fee {
+            true => "invoice_description_donor_fee",
+            false => "invoice_description",
         };
-        InvoiceReplyMessage {
+        Ok(InvoiceReplyMessage {
             method: "sendInvoice".to_string(),
             chat_id: self.chat_id,
-            title: "Spendenrechnung".to_string(),
-            description: format!(
-                "TEST-Rechnung für eine Spende in Höhe von {:.2}€ an deine Lieblingskneipe.\n(Der Betrag enthält etwa eine Gebühr von {:.2}€, der von dem Payment-Provider Stripe erhoben wird.)\nDIES IST EIN TEST!\nZAHLUNGEN SIND NOCH NICHT MÖGLICH!",
-                money_in_eur(self.get_damage()),
-                money_in_eur(calc_stripe_fee(self.get_damage()) as i64),
+            title: invoice_title(&self.texts.format("invoice_title", &[("pub", &chosen_pub.name)])),
+            description: self.texts.format(
+                description_key,
+                &[
+                    ("amount", &self.texts.money(damage)),
+                    ("pub", &chosen_pub.name),
+                    ("fee", &self.texts.money(stripe_fee)),
+                ],
             ),
             payload,
             provider_token,
@@ -250,18 +763,130 @@ impl BotContext {
             prices,
             // provider_data: Some("TODO what does stripe need?".to_string()),
             provider_data: None,
-            photo_url: Some("https://raw.githubusercontent.com/niilz/remoteDeckel/master/img/remoteDeckel-Logo.png".to_string()),
+            photo_url: Some(chosen_pub.logo_url.unwrap_or_else(|| "https://raw.githubusercontent.com/niilz/remoteDeckel/master/img/remoteDeckel-Logo.png".to_string())),
             // TODO: FIGURE OUT how to apply phot_size/width/height
             photo_size: 1000,
             photo_width: 300,
             photo_height: 300,
-            reply_markup: InlineKeyboardMarkup::new(money_in_eur(self.get_damage())),
+            reply_markup: InlineKeyboardMarkup::new(
+                &self.texts.format("invoice_pay_button", &[("amount", &self.texts.money(total))]),
+            ),
+        })
+    }
+}
+
+fn member_names(members: &[models::User]) -> String {
+    members
+        .iter()
+        .map(|member| member.first_name.as_str())
+        .collect::<Vec<&str>>()
+        .join(", ")
+}
+
+/// Reads a typed price like "1,75", "2.20 €", "2€" or "EUR 1.5" as cents.
+/// Only whole cents are accepted, so there may be at most two decimals.
+pub fn parse_price(text: &str) -> Result<i64, PriceError> {
+    let amount = text.to_lowercase().replace("€", "").replace("eur", "");
+    let amount = amount.trim();
+    let mut parts = amount.splitn(2, |c| c == ',' || c == '.');
+    let euros = parts.next().unwrap_or("");
+    let cents = parts.next();
+
+    let is_number = |part: &str| part.chars().all(|c| c.is_ascii_digit());
+    // Limits the length, so the number can not overflow
+    if euros.len() > 6 || !is_number(euros) {
+        return Err(PriceError::NotAPrice);
+    }
+    let cents = match cents {
+        None if euros.is_empty() => return Err(PriceError::NotAPrice),
+        None => 0,
+        Some(cents) if cents.is_empty() || cents.len() > 2 || !is_number(cents) => {
+            return Err(PriceError::NotAPrice)
         }
+        // "1,5" means 1,50€
+        Some(cents) if cents.len() == 1 => cents.parse::<i64>().unwrap_or(0) * 10,
+        Some(cents) => cents.parse::<i64>().unwrap_or(0),
+    };
+    let euros = match euros {
+        // ",50" means 0,50€
+        "" => 0,
+        euros => euros.parse::<i64>().map_err(|_| PriceError::NotAPrice)?,
+    };
+    validate_price(euros * 100 + cents)
+}
+
+// Also checks the prices of buttons, because callback_data can be forged
+pub fn validate_price(cents: i64) -> Result<i64, PriceError> {
+    match cents {
+        cents if cents < MIN_PRICE => Err(PriceError::TooLow),
+        cents if cents > MAX_PRICE => Err(PriceError::TooHigh),
+        cents => Ok(cents),
+    }
+}
+
+// Invoice titles must not be longer than 32 characters
+fn invoice_title(title: &str) -> String {
+    match title.chars().count() > 32 {
+        true => title.chars().take(31).chain(std::iter::once('…')).collect(),
+        false => title.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_price_accepts_comma_and_dot() {
+        assert_eq!(parse_price("1,75"), Ok(175));
+        assert_eq!(parse_price("1.75"), Ok(175));
+        assert_eq!(parse_price("2"), Ok(200));
+    }
+
+    #[test]
+    fn parse_price_reads_one_decimal_as_tens_of_cents() {
+        assert_eq!(parse_price("1,5"), Ok(150));
+        assert_eq!(parse_price("0.5"), Ok(50));
     }
 
+    #[test]
+    fn parse_price_accepts_missing_euros() {
+        assert_eq!(parse_price(",50"), Ok(50));
+        assert_eq!(parse_price(".5"), Ok(50));
+    }
+
+    #[test]
+    fn parse_price_ignores_currency_and_whitespace() {
+        assert_eq!(parse_price("2.20 €"), Ok(220));
+        assert_eq!(parse_price("2€"), Ok(200));
+        assert_eq!(parse_price("EUR 1.5"), Ok(150));
+        assert_eq!(parse_price("  3,00 eur "), Ok(300));
+    }
+
+    #[test]
+    fn parse_price_rejects_what_is_not_a_price() {
+        for text in &[
+            "", "€", ",", "1,", "1,555", "1,5,5", "1.000,00", "abc", "-1", "1 5", "1,x", "1234567",
+        ] {
+            assert_eq!(parse_price(text), Err(PriceError::NotAPrice), "{}", text);
+        }
+    }
+
+    #[test]
+    fn parse_price_checks_the_bounds() {
+        assert_eq!(parse_price("0,09"), Err(PriceError::TooLow));
+        assert_eq!(parse_price("0,10"), Ok(10));
+        assert_eq!(parse_price("10,00"), Ok(1000));
+        assert_eq!(parse_price("10,01"), Err(PriceError::TooHigh));
+        assert_eq!(parse_price("999999"), Err(PriceError::TooHigh));
+    }
 
-    fn get_damage_net(&self) -> i32 {
-        let damage = self.get_damage();
-        damage as i32 - calc_stripe_fee(damage)
+    #[test]
+    fn validate_price_bounds_are_inclusive() {
+        assert_eq!(validate_price(MIN_PRICE - 1), Err(PriceError::TooLow));
+        assert_eq!(validate_price(MIN_PRICE), Ok(MIN_PRICE));
+        assert_eq!(validate_price(MAX_PRICE), Ok(MAX_PRICE));
+        assert_eq!(validate_price(MAX_PRICE + 1), Err(PriceError::TooHigh));
+        assert_eq!(validate_price(-100), Err(PriceError::TooLow));
     }
 }
@@ -1,12 +1,20 @@
 use crate::bot_types::RequestType::*;
-use crate::telegram_types::ReplyKeyboardMarkup;
+use crate::i18n::{Language, Texts};
+use crate::models::{Drink, Order, Pub};
+use crate::telegram_types::{InlineKeyboardButton, InlineKeyboardMarkup};
 use serde::{Deserialize, Serialize};
 
 #[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub enum RequestType {
     Start,
     Terms,
-    Order,
+    // Holds the id of the ordered drink
+    Order(i32),
+    // Holds the id of the drink, every present member of the group gets one
+    Round(i32),
+    // Marks the user as present in the group
+    JoinRound,
+    UndoOrder,
     ShowDamage,
     BillPlease,
     PayNo,
@@ -17,49 +25,187 @@ pub enum RequestType {
     DeleteNo,
     DeleteYes,
     ChangePrice,
-    NewPrice,
+    // Holds the new price in cents
+    NewPrice(i64),
+    // A price typed by the user after ChangePrice, it is read from the message
+    EnteredPrice,
+    ChangePub,
+    // Holds the id of the chosen pub
+    NewPub(i32),
+    ChangeLanguage,
+    NewLanguage(Language),
+    ChangeFee,
+    // True if the donor pays the Stripe fee on top of the tab
+    NewFee(bool),
     ShowLast,
     ShowTotal,
     ShowTotalAll,
+    // A button, that does not fit the state of the conversation (anymore)
+    Expired,
     Unknown,
 }
 
+/// Buttons send the action id of their RequestType as callback_data.
+/// Unlike the button labels, action ids must never change, because old messages still carry them.
+impl RequestType {
+    pub fn action_id(&self) -> String {
+        match self {
+            Start => "start".to_string(),
+            Terms => "terms".to_string(),
+            Order(drink_id) => format!("order:{}", drink_id),
+            Round(drink_id) => format!("round:{}", drink_id),
+            JoinRound => "join".to_string(),
+            UndoOrder => "undo".to_string(),
+            ShowDamage => "damage".to_string(),
+            BillPlease => "bill".to_string(),
+            PayNo => "pay_no".to_string(),
+            PayYes => "pay_yes".to_string(),
+            Steal => "steal".to_string(),
+            Options => "options".to_string(),
+            DeletePlease => "delete".to_string(),
+            DeleteNo => "delete_no".to_string(),
+            DeleteYes => "delete_yes".to_string(),
+            ChangePrice => "price".to_string(),
+            NewPrice(price) => format!("price:{}", price),
+            // Never sent by a button
+            EnteredPrice => "entered_price".to_string(),
+            ChangePub => "pub".to_string(),
+            NewPub(pub_id) => format!("pub:{}", pub_id),
+            ChangeLanguage => "language".to_string(),
+            NewLanguage(language) => format!("language:{}", language.code()),
+            ChangeFee => "fee".to_string(),
+            NewFee(true) => "fee:donor".to_string(),
+            NewFee(false) => "fee:pub".to_string(),
+            ShowLast => "last".to_string(),
+            ShowTotal => "total".to_string(),
+            ShowTotalAll => "total_all".to_string(),
+            // Never sent by a button
+            Expired => "expired".to_string(),
+            Unknown => "unknown".to_string(),
+        }
+    }
+
+    pub fn from_action_id(action_id: &str) -> RequestType {
+        let mut parts = action_id.splitn(2, ':');
+        let action = parts.next().unwrap_or("");
+        match (action, parts.next()) {
+            ("start", None) => Start,
+            ("terms", None) => Terms,
+            ("order", Some(drink_id)) => drink_id.parse().map(Order).unwrap_or(Unknown),
+            ("round", Some(drink_id)) => drink_id.parse().map(Round).unwrap_or(Unknown),
+            ("join", None) => JoinRound,
+            ("undo", None) => UndoOrder,
+            ("damage", None) => ShowDamage,
+            ("bill", None) => BillPlease,
+            ("pay_no", None) => PayNo,
+            ("pay_yes", None) => PayYes,
+            ("steal", None) => Steal,
+            ("options", None) => Options,
+            ("delete", None) => DeletePlease,
+            ("delete_no", None) => DeleteNo,
+            ("delete_yes", None) => DeleteYes,
+            ("price", None) => ChangePrice,
+            ("price", Some(price)) => price.parse().map(NewPrice).unwrap_or(Unknown),
+            ("pub", None) => ChangePub,
+            ("pub", Some(pub_id)) => pub_id.parse().map(NewPub).unwrap_or(Unknown),
+            ("language", None) => ChangeLanguage,
+            ("language", Some(code)) => Language::from_code(code)
+                .map(NewLanguage)
+                .unwrap_or(Unknown),
+            ("fee", None) => ChangeFee,
+            ("fee", Some("donor")) => NewFee(true),
+            ("fee", Some("pub")) => NewFee(false),
+            ("last", None) => ShowLast,
+            ("total", None) => ShowTotal,
+            ("total_all", None) => ShowTotalAll,
+            _ => Unknown,
+        }
+    }
+}
+
 pub struct Keyboards {
     pub main: Vec<(RequestType, String)>,
     pub pay: Vec<(RequestType, String)>,
     pub delete: Vec<(RequestType, String)>,
     pub options: Vec<(RequestType, String)>,
     pub price: Vec<(RequestType, String)>,
+    pub pubs: Vec<(RequestType, String)>,
+    pub languages: Vec<(RequestType, String)>,
+    pub fees: Vec<(RequestType, String)>,
 }
 impl Keyboards {
-    pub fn init() -> Self {
+    // The labels are taken from the texts, so the keyboards speak the language of the user.
+    // In groups rounds can be ordered as well.
+    pub fn init(drinks: &[Drink], pubs: &[Pub], texts: &Texts, in_group: bool) -> Self {
         let mut main = Vec::new();
-        main.push((Order, "🍺 Bring mir ein Bier! 🍺".to_string()));
-        main.push((ShowDamage, "😬 Was is mein Schaden? 😬".to_string()));
-        main.push((BillPlease, "🙈 Augen zu und zahlen. 💶".to_string()));
-        main.push((Options, "⚙ Optionen ⚙".to_string()));
+        for drink in drinks {
+            main.push((
+                Order(drink.id),
+                texts.format(
+                    "button_order",
+                    &[("emoji", &drink.emoji), ("drink", &texts.drink_name(drink))],
+                ),
+            ));
+        }
+        if in_group {
+            for drink in drinks {
+                main.push((
+                    Round(drink.id),
+                    texts.format(
+                        "button_round",
+                        &[("emoji", &drink.emoji), ("drink", &texts.drink_name(drink))],
+                    ),
+                ));
+            }
+            main.push((JoinRound, texts.get("button_join")));
+        }
+        main.push((UndoOrder, texts.get("button_undo")));
+        main.push((ShowDamage, texts.get("button_damage")));
+        main.push((BillPlease, texts.get("button_bill")));
+        main.push((Options, texts.get("button_options")));
 
         let mut pay = Vec::new();
-        pay.push((PayYes, "✅ JA! Jetzt spenden ✅".to_string()));
-        pay.push((PayNo, "❌ NEIN! Noch nicht spenden ❌".to_string()));
-        pay.push((Steal, "👻 Zeche prellen... 🤫".to_string()));
+        pay.push((PayYes, texts.get("button_pay_yes")));
+        pay.push((PayNo, texts.get("button_pay_no")));
+        pay.push((Steal, texts.get("button_steal")));
 
         let mut delete = Vec::new();
-        delete.push((DeleteYes, "✅ JA! Daten löschen ✅".to_string()));
-        delete.push((DeleteNo, "❌ NEIN! Daten nicht löschen ❌".to_string()));
+        delete.push((DeleteYes, texts.get("button_delete_yes")));
+        delete.push((DeleteNo, texts.get("button_delete_no")));
 
         let mut options = Vec::new();
-        options.push((ChangePrice, "€ Preis ändern €".to_string()));
-        options.push((ShowLast, "⌚ Meine letzte Spende ⌚".to_string()));
-        options.push((ShowTotal, "➕ Summe meiner Spenden ➕".to_string()));
-        options.push((ShowTotalAll, "➕➕Summe aller Spenden➕➕".to_string()));
-        options.push((DeletePlease, "😱 Lösche meine Daten 😱".to_string()));
-
-        let mut price = Vec::new();
-        price.push((NewPrice, "0,50€".to_string()));
-        price.push((NewPrice, "1,00€".to_string()));
-        price.push((NewPrice, "1,50€".to_string()));
-        price.push((NewPrice, "2,00€".to_string()));
+        options.push((ChangePrice, texts.get("button_change_price")));
+        options.push((ChangePub, texts.get("button_change_pub")));
+        options.push((ChangeLanguage, texts.get("button_change_language")));
+        options.push((ChangeFee, texts.get("button_change_fee")));
+        options.push((ShowLast, texts.get("button_show_last")));
+        options.push((ShowTotal, texts.get("button_show_total")));
+        options.push((ShowTotalAll, texts.get("button_show_total_all")));
+        options.push((DeletePlease, texts.get("button_delete")));
+
+        let price = [50, 100, 150, 200]
+            .iter()
+            .map(|&cents| (NewPrice(cents), format!("{}€", texts.money(cents))))
+            .collect();
+
+        let pubs = pubs
+            .iter()
+            .map(|the_pub| {
+                (
+                    NewPub(the_pub.id),
+                    texts.format("button_pub", &[("pub", &the_pub.name)]),
+                )
+            })
+            .collect();
+
+        let languages = Language::ALL
+            .iter()
+            .map(|&language| (NewLanguage(language), language.name().to_string()))
+            .collect();
+
+        let mut fees = Vec::new();
+        fees.push((NewFee(true), texts.get("button_fee_donor")));
+        fees.push((NewFee(false), texts.get("button_fee_pub")));
 
         Keyboards {
             main,
@@ -67,61 +213,32 @@ impl Keyboards {
             delete,
             options,
             price,
+            pubs,
+            languages,
+            fees,
         }
     }
 
-    pub fn get_request_type(&self, user_answer: &str) -> RequestType {
-        match get_request_type_by_answer(&self.main, user_answer) {
-            Some(req_typ) => req_typ,
-            None => match get_request_type_by_answer(&self.pay, user_answer) {
-                Some(req_typ) => req_typ,
-                None => match get_request_type_by_answer(&self.delete, user_answer) {
-                    Some(req_typ) => req_typ,
-                    None => match get_request_type_by_answer(&self.options, user_answer) {
-                        Some(req_typ) => req_typ,
-                        None => match get_request_type_by_answer(&self.price, user_answer) {
-                            Some(req_typ) => req_typ,
-                            None => RequestType::Unknown,
-                        },
-                    },
-                },
-            },
-        }
-    }
-
-    pub fn get_keyboard(&self, request_type: RequestType) -> ReplyKeyboardMarkup {
+    pub fn get_keyboard(&self, request_type: RequestType) -> InlineKeyboardMarkup {
         match request_type {
             RequestType::BillPlease => keyboard_factory(&self.pay),
             RequestType::DeletePlease => keyboard_factory(&self.delete),
             RequestType::Options => keyboard_factory(&self.options),
             RequestType::ChangePrice => keyboard_factory(&self.price),
+            RequestType::ChangePub => keyboard_factory(&self.pubs),
+            RequestType::ChangeLanguage => keyboard_factory(&self.languages),
+            RequestType::ChangeFee => keyboard_factory(&self.fees),
             _ => keyboard_factory(&self.main),
         }
     }
 }
 
-fn get_request_type_by_answer(
-    keyboard: &Vec<(RequestType, String)>,
-    user_response: &str,
-) -> Option<RequestType> {
-    keyboard.into_iter().find_map(|(req_typ, button)| {
-        if button == user_response {
-            Some(*req_typ)
-        } else {
-            None
-        }
-    })
-}
-
-pub fn keyboard_factory(keyboard: &Vec<(RequestType, String)>) -> ReplyKeyboardMarkup {
-    let keyboard: Vec<Vec<String>> = keyboard
+pub fn keyboard_factory(keyboard: &Vec<(RequestType, String)>) -> InlineKeyboardMarkup {
+    let inline_keyboard: Vec<Vec<InlineKeyboardButton>> = keyboard
         .iter()
-        .map(|(_req_typ, button)| vec![button.to_string()])
+        .map(|(req_typ, button)| vec![InlineKeyboardButton::callback(button, req_typ.action_id())])
         .collect();
-    ReplyKeyboardMarkup {
-        keyboard,
-        resize_keyboard: true,
-    }
+    InlineKeyboardMarkup { inline_keyboard }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -129,18 +246,72 @@ pub fn keyboard_factory(keyboard: &Vec<(RequestType, String)>) -> ReplyKeyboardM
 // Final String can NOT be longer than 128 characters!!!
 pub struct Payload {
     pub user_id: i32,
-    pub chat_id: i32,
+    // The chat, whose tab is payed (private or group)
+    pub chat_id: i64,
     pub total: i64,
     pub totals_sum: i64,
+    pub pub_id: i32,
+    // The estimated Stripe fee shown on the invoice (missing in invoices sent before it was added)
+    #[serde(default)]
+    pub fee: Option<i64>,
+    // The fee is included in total (the name is short, because of the length limit)
+    #[serde(rename = "dpf", default)]
+    pub donor_pays_fee: bool,
 }
 
 impl Payload {
-    pub fn new(user_id: i32, chat_id: i32, total: i64, totals_sum: i64) -> Self {
+    pub fn new(
+        user_id: i32,
+        chat_id: i64,
+        total: i64,
+        totals_sum: i64,
+        pub_id: i32,
+        fee: i64,
+        donor_pays_fee: bool,
+    ) -> Self {
         Payload {
             user_id,
             chat_id,
             total,
             totals_sum,
+            pub_id,
+            fee: Some(fee),
+            donor_pays_fee,
+        }
+    }
+
+    // The tab, that is payed with the invoice (total without the fee the donor pays on top)
+    pub fn tab(&self) -> i64 {
+        match (self.donor_pays_fee, self.fee) {
+            (true, Some(fee)) => self.total - fee,
+            _ => self.total,
+        }
+    }
+}
+
+// All open orders of one drink, summed up for display
+pub struct TabEntry {
+    pub drink: Drink,
+    pub drink_count: i16,
+    pub damage: i64,
+}
+
+impl TabEntry {
+    pub fn from_orders(open_orders: Vec<(Order, Drink)>) -> Vec<TabEntry> {
+        let mut tab: Vec<TabEntry> = Vec::new();
+        for (order, drink) in open_orders {
+            match tab.iter_mut().find(|entry| entry.drink.id == drink.id) {
+                Some(entry) => {
+                    entry.drink_count += 1;
+                    entry.damage += order.price.0;
+                }
+                None => tab.push(TabEntry {
+                    drink,
+                    drink_count: 1,
+                    damage: order.price.0,
+                }),
+            }
         }
+        tab
     }
 }
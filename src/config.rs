@@ -0,0 +1,404 @@
+use crate::error::{BotError, BotResult};
+use serde::Deserialize;
+use std::fmt;
+use std::fs;
+
+// The file is read from the working directory, unless DECKEL_CONFIG points somewhere else
+static DEFAULT_CONFIG_PATH: &str = "deckel.toml";
+// Every setting can be overridden with an env-variable named DECKEL_<SETTING> (e.g. DECKEL_API_KEY)
+static ENV_PREFIX: &str = "DECKEL_";
+static SETTINGS: [&str; 12] = [
+    "api_key",
+    "bot_username",
+    "provider_token",
+    "stripe_token",
+    "stripe_webhook_secret",
+    "webhook_secret",
+    "hosting_url",
+    "polling",
+    "admin_token",
+    "fee_eea_rate",
+    "fee_non_eea_rate",
+    "fee_fixed",
+];
+
+/// The environment is chosen with DECKEL_ENV (dev if not set).
+/// Each environment has its own table in the config file (e.g. [test]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Environment {
+    Dev,
+    // Uses the remoteDeckelTest_bot and Stripe's test mode
+    Test,
+    Prod,
+}
+
+impl Environment {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Environment::Dev => "dev",
+            Environment::Test => "test",
+            Environment::Prod => "prod",
+        }
+    }
+
+    fn from_env() -> BotResult<Environment> {
+        match std::env::var("DECKEL_ENV") {
+            Err(_) => Ok(Environment::Dev),
+            Ok(name) => match name.as_str() {
+                "dev" => Ok(Environment::Dev),
+                "test" => Ok(Environment::Test),
+                "prod" => Ok(Environment::Prod),
+                other => Err(BotError::Config(format!(
+                    "Unknown environment '{}' (dev, test or prod)",
+                    other
+                ))),
+            },
+        }
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// All settings of the bot. Is loaded (and validated) once at startup
+/// and handed to the handlers as Rocket managed state.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Config {
+    #[serde(skip_deserializing, default = "default_environment")]
+    pub environment: Environment,
+    // Token of the Telegram bot
+    pub api_key: String,
+    // Username of the bot (without @), commands in groups are only accepted as /command@bot_username
+    pub bot_username: String,
+    // Token of the payment provider (Stripe) connected to the bot, used for invoices
+    pub provider_token: String,
+    // Secret key of the Stripe account, used for transfers to the pubs
+    pub stripe_token: String,
+    pub stripe_webhook_secret: String,
+    // Sent by Telegram with every webhook-update (X-Telegram-Bot-Api-Secret-Token)
+    #[serde(default)]
+    pub webhook_secret: String,
+    // Public url the webhook is registered with (e.g. an ngrok-tunnel)
+    pub hosting_url: Option<String>,
+    // Receive updates via getUpdates instead of the webhook
+    #[serde(default)]
+    pub polling: bool,
+    // Sent with admin requests (X-Deckel-Admin-Token), the admin routes are disabled if empty
+    #[serde(default)]
+    pub admin_token: String,
+    // Stripe's fee per charge: a rate in basis points (140 = 1.4%) depending on the
+    // origin of the card, plus a fixed amount in cents
+    #[serde(default = "default_fee_eea_rate")]
+    pub fee_eea_rate: i64,
+    #[serde(default = "default_fee_non_eea_rate")]
+    pub fee_non_eea_rate: i64,
+    #[serde(default = "default_fee_fixed")]
+    pub fee_fixed: i64,
+}
+
+fn default_environment() -> Environment {
+    Environment::Dev
+}
+
+fn default_fee_eea_rate() -> i64 {
+    140
+}
+
+fn default_fee_non_eea_rate() -> i64 {
+    290
+}
+
+fn default_fee_fixed() -> i64 {
+    25
+}
+
+impl Config {
+    pub fn load() -> BotResult<Config> {
+        let environment = Environment::from_env()?;
+        let path =
+            std::env::var("DECKEL_CONFIG").unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+        let file_content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            // Without a file, all settings have to be set as env-variables
+            Err(e) => {
+                println!("Config file {} not read ({}), using env-variables", path, e);
+                String::new()
+            }
+        };
+        Config::from_toml(&file_content, environment)
+    }
+
+    fn from_toml(file_content: &str, environment: Environment) -> BotResult<Config> {
+        let mut file: toml::value::Table = toml::from_str(file_content)
+            .map_err(|e| BotError::Config(format!("Invalid config file: {}", e)))?;
+        let mut settings = match file.remove(environment.as_str()) {
+            Some(toml::Value::Table(settings)) => settings,
+            Some(_) => {
+                return Err(BotError::Config(format!(
+                    "[{}] in config file is not a table",
+                    environment
+                )))
+            }
+            None => toml::value::Table::new(),
+        };
+
+        for setting in SETTINGS.iter() {
+            let key = format!("{}{}", ENV_PREFIX, setting.to_uppercase());
+            if let Ok(value) = std::env::var(&key) {
+                let value = match *setting {
+                    "polling" => toml::Value::Boolean(value.parse().map_err(|_| {
+                        BotError::Config(format!("{} must be true or false but is {}", key, value))
+                    })?),
+                    "fee_eea_rate" | "fee_non_eea_rate" | "fee_fixed" => {
+                        toml::Value::Integer(value.parse().map_err(|_| {
+                            BotError::Config(format!("{} must be a number but is {}", key, value))
+                        })?)
+                    }
+                    _ => toml::Value::String(value),
+                };
+                settings.insert(setting.to_string(), value);
+            }
+        }
+
+        let mut config: Config = toml::Value::Table(settings)
+            .try_into()
+            .map_err(|e| BotError::Config(format!("[{}] {}", environment, e)))?;
+        config.environment = environment;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> BotResult<()> {
+        let required = [
+            ("api_key", &self.api_key),
+            ("bot_username", &self.bot_username),
+            ("provider_token", &self.provider_token),
+            ("stripe_token", &self.stripe_token),
+            ("stripe_webhook_secret", &self.stripe_webhook_secret),
+        ];
+        if let Some((setting, _)) = required.iter().find(|(_, value)| value.trim().is_empty()) {
+            return Err(BotError::Config(format!(
+                "[{}] {} must not be empty",
+                self.environment, setting
+            )));
+        }
+        let fees = [
+            ("fee_eea_rate", self.fee_eea_rate),
+            ("fee_non_eea_rate", self.fee_non_eea_rate),
+            ("fee_fixed", self.fee_fixed),
+        ];
+        // A rate of 100% would leave nothing of a donation
+        if let Some((setting, fee)) = fees.iter().find(|(_, fee)| !(0..10_000).contains(fee)) {
+            return Err(BotError::Config(format!(
+                "[{}] {} must be between 0 and 9999 but is {}",
+                self.environment, setting, fee
+            )));
+        }
+        if let Some(url) = &self.hosting_url {
+            // Telegram only sends updates to https-webhooks
+            if !url.starts_with("https://") {
+                return Err(BotError::Config(format!(
+                    "[{}] hosting_url must start with https:// but is {}",
+                    self.environment, url
+                )));
+            }
+            if !self.polling && !is_valid_secret_token(&self.webhook_secret) {
+                return Err(BotError::Config(format!(
+                    "[{}] webhook_secret must be 1-256 characters of A-Z, a-z, 0-9, _ and -",
+                    self.environment
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_test(&self) -> bool {
+        self.environment == Environment::Test
+    }
+}
+
+// Telegram's rules for the secret_token of setWebhook
+fn is_valid_secret_token(token: &str) -> bool {
+    (1..=256).contains(&token.len())
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use once_cell::sync::Lazy;
+    use std::sync::{Mutex, MutexGuard};
+
+    // The env-variables are shared by all tests, the tests reading them must not run in parallel
+    static ENV: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+    static FILE: &str = r#"
+        [dev]
+        api_key = "dev-key"
+        bot_username = "dev_bot"
+        provider_token = "provider"
+        stripe_token = "sk_test"
+        stripe_webhook_secret = "whsec_dev"
+        polling = true
+
+        [test]
+        api_key = "test-key"
+        bot_username = "remoteDeckelTest_bot"
+        provider_token = "provider"
+        stripe_token = "sk_test"
+        stripe_webhook_secret = "whsec_test"
+        webhook_secret = "telegram-secret_1"
+        hosting_url = "https://tunnelurl.com"
+        fee_fixed = 30
+    "#;
+
+    fn lock_env() -> MutexGuard<'static, ()> {
+        ENV.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn error_of(file_content: &str, environment: Environment) -> String {
+        match Config::from_toml(file_content, environment) {
+            Err(BotError::Config(message)) => message,
+            Err(e) => panic!("Unexpected error {}", e),
+            Ok(config) => panic!("Config is valid: {:?}", config),
+        }
+    }
+
+    #[test]
+    fn reads_the_table_of_the_environment() {
+        let _env = lock_env();
+        let dev = Config::from_toml(FILE, Environment::Dev).unwrap();
+        assert_eq!(dev.environment, Environment::Dev);
+        assert_eq!(dev.api_key, "dev-key");
+        assert!(dev.polling);
+        assert_eq!(dev.hosting_url, None);
+
+        let test = Config::from_toml(FILE, Environment::Test).unwrap();
+        assert_eq!(test.environment, Environment::Test);
+        assert_eq!(test.api_key, "test-key");
+        assert!(!test.polling);
+        assert_eq!(test.hosting_url.as_deref(), Some("https://tunnelurl.com"));
+    }
+
+    #[test]
+    fn fees_default_to_stripes_prices() {
+        let _env = lock_env();
+        let dev = Config::from_toml(FILE, Environment::Dev).unwrap();
+        assert_eq!(
+            (dev.fee_eea_rate, dev.fee_non_eea_rate, dev.fee_fixed),
+            (140, 290, 25)
+        );
+        let test = Config::from_toml(FILE, Environment::Test).unwrap();
+        assert_eq!(test.fee_fixed, 30);
+    }
+
+    #[test]
+    fn a_missing_environment_needs_all_required_settings() {
+        let _env = lock_env();
+        assert!(error_of(FILE, Environment::Prod).starts_with("[prod] "));
+        assert!(error_of("", Environment::Prod).starts_with("[prod] "));
+    }
+
+    #[test]
+    fn required_settings_must_not_be_empty() {
+        let _env = lock_env();
+        let file = FILE.replace(r#"bot_username = "dev_bot""#, r#"bot_username = " ""#);
+        assert_eq!(
+            error_of(&file, Environment::Dev),
+            "[dev] bot_username must not be empty"
+        );
+    }
+
+    #[test]
+    fn fees_must_be_in_range() {
+        let _env = lock_env();
+        let file = FILE.replace("fee_fixed = 30", "fee_fixed = -1");
+        assert_eq!(
+            error_of(&file, Environment::Test),
+            "[test] fee_fixed must be between 0 and 9999 but is -1"
+        );
+        let file = FILE.replace("fee_fixed = 30", "fee_eea_rate = 10000");
+        assert_eq!(
+            error_of(&file, Environment::Test),
+            "[test] fee_eea_rate must be between 0 and 9999 but is 10000"
+        );
+        let file = FILE.replace("fee_fixed = 30", "fee_non_eea_rate = 9999");
+        assert_eq!(
+            Config::from_toml(&file, Environment::Test)
+                .unwrap()
+                .fee_non_eea_rate,
+            9999
+        );
+    }
+
+    #[test]
+    fn the_webhook_needs_https() {
+        let _env = lock_env();
+        let file = FILE.replace("https://tunnelurl.com", "http://tunnelurl.com");
+        assert_eq!(
+            error_of(&file, Environment::Test),
+            "[test] hosting_url must start with https:// but is http://tunnelurl.com"
+        );
+    }
+
+    #[test]
+    fn the_webhook_needs_a_valid_secret() {
+        let _env = lock_env();
+        for secret in &["", "with space", "ümlaut"] {
+            let file = FILE.replace("telegram-secret_1", secret);
+            assert!(error_of(&file, Environment::Test).contains("webhook_secret"));
+        }
+        // Polled updates are not sent with a secret
+        let file = FILE.replace(r#"webhook_secret = "telegram-secret_1""#, "polling = true");
+        assert!(Config::from_toml(&file, Environment::Test).is_ok());
+    }
+
+    #[test]
+    fn env_variables_override_the_file() {
+        let _env = lock_env();
+        std::env::set_var("DECKEL_API_KEY", "env-key");
+        std::env::set_var("DECKEL_POLLING", "false");
+        std::env::set_var("DECKEL_FEE_FIXED", "35");
+        let config = Config::from_toml(FILE, Environment::Dev);
+        std::env::remove_var("DECKEL_API_KEY");
+        std::env::remove_var("DECKEL_POLLING");
+        std::env::remove_var("DECKEL_FEE_FIXED");
+
+        let config = config.unwrap();
+        assert_eq!(config.api_key, "env-key");
+        assert!(!config.polling);
+        assert_eq!(config.fee_fixed, 35);
+    }
+
+    #[test]
+    fn invalid_env_variables_are_rejected() {
+        let _env = lock_env();
+        std::env::set_var("DECKEL_POLLING", "yes");
+        let polling_error = error_of(FILE, Environment::Dev);
+        std::env::remove_var("DECKEL_POLLING");
+        std::env::set_var("DECKEL_FEE_EEA_RATE", "1.4");
+        let fee_error = error_of(FILE, Environment::Dev);
+        std::env::remove_var("DECKEL_FEE_EEA_RATE");
+
+        assert_eq!(
+            polling_error,
+            "DECKEL_POLLING must be true or false but is yes"
+        );
+        assert_eq!(fee_error, "DECKEL_FEE_EEA_RATE must be a number but is 1.4");
+    }
+
+    #[test]
+    fn an_invalid_file_is_rejected() {
+        let _env = lock_env();
+        assert!(error_of("[dev", Environment::Dev).starts_with("Invalid config file: "));
+        assert_eq!(
+            error_of("dev = 1", Environment::Dev),
+            "[dev] in config file is not a table"
+        );
+    }
+}
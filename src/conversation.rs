@@ -0,0 +1,195 @@
+use crate::bot_types::RequestType::{self, *};
+use crate::i18n::Texts;
+use crate::models::{Conversation, ConversationState};
+use chrono::{DateTime, Utc};
+
+// A flow, that has not been continued for this many seconds, is over
+pub static STATE_TIMEOUT: i64 = 10 * 60;
+
+/// The state of the conversation at the given time. A state older than the timeout is idle again.
+pub fn current_state(conversation: Option<&Conversation>, now: DateTime<Utc>) -> ConversationState {
+    match conversation {
+        Some(conversation)
+            if (now - conversation.state_changed_at).num_seconds() <= STATE_TIMEOUT =>
+        {
+            conversation.state
+        }
+        _ => ConversationState::Idle,
+    }
+}
+
+/// Answers of a flow are only accepted in the state, that asks for them.
+/// This rejects buttons of old messages (e.g. a stale "Daten löschen").
+/// Everything else is accepted in every state and ends the flow.
+pub fn accepts(state: ConversationState, request_type: RequestType) -> bool {
+    match request_type {
+        PayYes | PayNo | Steal => state == ConversationState::ConfirmingPayment,
+        DeleteYes | DeleteNo => state == ConversationState::ConfirmingDelete,
+        NewPrice(_) | EnteredPrice => state == ConversationState::AwaitingPrice,
+        _ => true,
+    }
+}
+
+/// The state after the request has been answered (with the keyboard of this request type).
+pub fn next_state(request_type: RequestType) -> ConversationState {
+    match request_type {
+        BillPlease => ConversationState::ConfirmingPayment,
+        DeletePlease => ConversationState::ConfirmingDelete,
+        ChangePrice => ConversationState::AwaitingPrice,
+        _ => ConversationState::Idle,
+    }
+}
+
+/// Typed messages are read in the context of the conversation,
+/// e.g. "Ja" confirms the payment after the bill has been shown.
+pub fn interpret_text(state: ConversationState, text: &str, texts: &Texts) -> Option<RequestType> {
+    match state {
+        ConversationState::Idle => None,
+        ConversationState::AwaitingPrice => Some(EnteredPrice),
+        ConversationState::ConfirmingPayment => {
+            read_answer(text, texts).map(|yes| if yes { PayYes } else { PayNo })
+        }
+        ConversationState::ConfirmingDelete => {
+            read_answer(text, texts).map(|yes| if yes { DeleteYes } else { DeleteNo })
+        }
+    }
+}
+
+// Some(true) for yes, Some(false) for no and None for anything else
+fn read_answer(text: &str, texts: &Texts) -> Option<bool> {
+    let answer = text
+        .trim()
+        .trim_end_matches(|c| c == '!' || c == '.')
+        .to_lowercase();
+    let is_one_of = |key: &str| texts.get(key).split('|').any(|word| word == answer);
+    match (is_one_of("answers_yes"), is_one_of("answers_no")) {
+        (true, _) => Some(true),
+        (_, true) => Some(false),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::i18n::Language;
+    use crate::models::ConversationState::*;
+    use chrono::Duration;
+
+    static ALL: [ConversationState; 4] = [Idle, AwaitingPrice, ConfirmingPayment, ConfirmingDelete];
+
+    fn conversation(state: ConversationState, changed_at: DateTime<Utc>) -> Conversation {
+        Conversation {
+            user_id: 1,
+            chat_id: 1,
+            state,
+            state_changed_at: changed_at,
+        }
+    }
+
+    fn accepted_in(request_type: RequestType) -> Vec<ConversationState> {
+        ALL.iter()
+            .cloned()
+            .filter(|state| accepts(*state, request_type))
+            .collect()
+    }
+
+    #[test]
+    fn answers_are_only_accepted_in_their_state() {
+        assert_eq!(accepted_in(PayYes), vec![ConfirmingPayment]);
+        assert_eq!(accepted_in(PayNo), vec![ConfirmingPayment]);
+        assert_eq!(accepted_in(Steal), vec![ConfirmingPayment]);
+        assert_eq!(accepted_in(DeleteYes), vec![ConfirmingDelete]);
+        assert_eq!(accepted_in(DeleteNo), vec![ConfirmingDelete]);
+        assert_eq!(accepted_in(NewPrice(250)), vec![AwaitingPrice]);
+        assert_eq!(accepted_in(EnteredPrice), vec![AwaitingPrice]);
+    }
+
+    #[test]
+    fn stale_confirmation_taps_are_rejected() {
+        // The bill has been paid (or the flow ended) before the old button is tapped again
+        assert!(!accepts(Idle, PayYes));
+        assert!(!accepts(ConfirmingDelete, PayYes));
+        // The deletion has been asked for after the bill, the old bill button must not pay
+        assert!(!accepts(ConfirmingDelete, Steal));
+        assert!(!accepts(ConfirmingPayment, DeleteYes));
+        assert!(!accepts(Idle, DeleteYes));
+    }
+
+    #[test]
+    fn commands_are_accepted_in_every_state() {
+        for request_type in [
+            Start,
+            ShowDamage,
+            BillPlease,
+            DeletePlease,
+            ChangePrice,
+            Order(1),
+        ]
+        .iter()
+        {
+            assert_eq!(accepted_in(*request_type), ALL.to_vec());
+        }
+    }
+
+    #[test]
+    fn states_time_out() {
+        let now = Utc::now();
+        let recent = conversation(ConfirmingPayment, now - Duration::seconds(STATE_TIMEOUT));
+        let old = conversation(
+            ConfirmingPayment,
+            now - Duration::seconds(STATE_TIMEOUT + 1),
+        );
+        assert_eq!(current_state(Some(&recent), now), ConfirmingPayment);
+        assert_eq!(current_state(Some(&old), now), Idle);
+        assert_eq!(current_state(None, now), Idle);
+    }
+
+    #[test]
+    fn a_timed_out_confirmation_is_stale() {
+        let now = Utc::now();
+        let old = conversation(ConfirmingDelete, now - Duration::seconds(STATE_TIMEOUT + 1));
+        assert!(!accepts(current_state(Some(&old), now), DeleteYes));
+    }
+
+    #[test]
+    fn flows_lead_to_their_states() {
+        assert_eq!(next_state(BillPlease), ConfirmingPayment);
+        assert_eq!(next_state(DeletePlease), ConfirmingDelete);
+        assert_eq!(next_state(ChangePrice), AwaitingPrice);
+        assert_eq!(next_state(PayYes), Idle);
+        assert_eq!(next_state(DeleteYes), Idle);
+        assert_eq!(next_state(EnteredPrice), Idle);
+    }
+
+    #[test]
+    fn typed_answers_confirm_in_both_languages() {
+        let de = Texts::new(Language::De);
+        let en = Texts::new(Language::En);
+        assert!(interpret_text(ConfirmingPayment, "Ja!", &de) == Some(PayYes));
+        assert!(interpret_text(ConfirmingPayment, " nö ", &de) == Some(PayNo));
+        assert!(interpret_text(ConfirmingPayment, "Yes.", &en) == Some(PayYes));
+        assert!(interpret_text(ConfirmingDelete, "NOPE", &en) == Some(DeleteNo));
+        assert!(interpret_text(ConfirmingDelete, "ok", &de) == Some(DeleteYes));
+    }
+
+    #[test]
+    fn other_texts_are_no_answer() {
+        let de = Texts::new(Language::De);
+        let en = Texts::new(Language::En);
+        assert!(interpret_text(ConfirmingPayment, "vielleicht", &de).is_none());
+        assert!(interpret_text(ConfirmingPayment, "janein", &de).is_none());
+        assert!(interpret_text(ConfirmingDelete, "", &de).is_none());
+        // The answers of the other language are not understood
+        assert!(interpret_text(ConfirmingPayment, "yes", &de).is_none());
+        assert!(interpret_text(ConfirmingDelete, "ja", &en).is_none());
+    }
+
+    #[test]
+    fn texts_are_only_answers_in_a_flow() {
+        let de = Texts::new(Language::De);
+        assert!(interpret_text(Idle, "ja", &de).is_none());
+        assert!(interpret_text(AwaitingPrice, "2,50", &de) == Some(EnteredPrice));
+        assert!(interpret_text(AwaitingPrice, "ja", &de) == Some(EnteredPrice));
+    }
+}
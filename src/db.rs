@@ -1,6 +1,30 @@
+use crate::error::{BotError, BotResult};
 use crate::models;
-use crate::schema::payments::dsl::{id as pay_id, payments, transfer_id};
-use crate::schema::users::dsl::{id, total, users};
+use crate::schema::conversations::dsl::{
+    chat_id as conversation_chat_id, conversations, state as conversation_state,
+    state_changed_at as conversation_state_changed_at, user_id as conversation_user_id,
+};
+use crate::schema::drinks::dsl::{active, drinks, id as drinks_id};
+use crate::schema::group_members::dsl::{
+    chat_id as member_chat_id, group_members, last_seen_at, user_id as member_user_id,
+};
+use crate::schema::orders::dsl::{
+    chat_id as order_chat_id, drink_id as order_drink_id, id as orders_id, ordered_at, orders,
+    payment_id as order_payment_id, price as order_price, user_id as order_user_id,
+};
+use crate::schema::payment_fees::dsl::payment_fees;
+use crate::schema::payments::dsl::{
+    donor_paid_fee, fee as pay_fee, id as pay_id, last_error, next_attempt_at, payed_amount,
+    payed_at, payments, receipt_identifier, status as pay_status, status_changed_at,
+    transfer_attempts, transfer_id, user_id as pay_user_id,
+};
+use crate::schema::processed_updates::dsl::{processed_at, processed_updates, update_id};
+use crate::schema::pubs::dsl::{active as pub_active, id as pubs_id, pubs};
+use crate::schema::refunds::dsl::refunds;
+use crate::schema::spending_limits::dsl::{spending_limits, user_id as limit_user_id};
+use crate::schema::users::all_columns as user_columns;
+use crate::schema::users::dsl::{active as active_user, first_name, id, total, users};
+use chrono::{DateTime, Utc};
 use diesel::data_types::PgMoney;
 use diesel::prelude::*;
 use rocket_contrib::databases::diesel::PgConnection;
@@ -8,59 +32,447 @@ use rocket_contrib::databases::diesel::PgConnection;
 #[database("remote_deckel")]
 pub struct UserDbConn(PgConnection);
 
-pub fn save_user(new_user: models::NewUser, conn: &PgConnection) -> models::User {
-    let new_user = diesel::insert_into(users)
-        .values(new_user)
-        .get_result(conn)
-        .expect("Error saving user.");
-    new_user
+pub fn save_user(new_user: models::NewUser, conn: &PgConnection) -> QueryResult<models::User> {
+    diesel::insert_into(users).values(new_user).get_result(conn)
 }
 
-pub fn get_user_by_id(
-    given_id: i32,
-    conn: &PgConnection,
-) -> Result<models::User, diesel::result::Error> {
+pub fn get_user_by_id(given_id: i32, conn: &PgConnection) -> QueryResult<models::User> {
     users.find(given_id).first(conn)
 }
 
-pub fn update_user(user_id: i32, update_user: &models::UpdateUser, conn: &PgConnection) -> usize {
-    let updated_count = diesel::update(users)
+pub fn update_user(
+    user_id: i32,
+    update_user: &models::UpdateUser,
+    conn: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::update(users)
         .filter(id.eq(user_id))
         .set(update_user)
         .execute(conn)
-        .expect("Could not update user by given UpdateUser");
-    updated_count
 }
 
-pub fn delete_user(user: &models::User, conn: &PgConnection) -> usize {
-    let deleted_count = diesel::delete(user)
+pub fn set_user_active(user_id: i32, is_active: bool, conn: &PgConnection) -> QueryResult<usize> {
+    diesel::update(users)
+        .filter(id.eq(user_id))
+        .set(active_user.eq(is_active))
         .execute(conn)
-        .expect("Could not delete given user");
-    deleted_count
 }
 
-pub fn get_total_all(conn: &PgConnection) -> Vec<PgMoney> {
-    users
-        .select(total)
-        .load::<PgMoney>(conn)
-        .expect("Could not sum the total of all users")
+pub fn delete_user(user: &models::User, conn: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(user).execute(conn)
+}
+
+pub fn get_total_all(conn: &PgConnection) -> QueryResult<Vec<PgMoney>> {
+    users.select(total).load::<PgMoney>(conn)
+}
+
+// CONVERSATIONS
+pub fn get_conversation(
+    user_id: i32,
+    chat_id: i64,
+    conn: &PgConnection,
+) -> QueryResult<Option<models::Conversation>> {
+    conversations
+        .find((user_id, chat_id))
+        .first(conn)
+        .optional()
+}
+
+// Creates the conversation on the first state change
+pub fn save_conversation(
+    conversation: &models::NewConversation,
+    conn: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::insert_into(conversations)
+        .values(conversation)
+        .on_conflict((conversation_user_id, conversation_chat_id))
+        .do_update()
+        .set((
+            conversation_state.eq(conversation.state),
+            conversation_state_changed_at.eq(conversation.state_changed_at),
+        ))
+        .execute(conn)
+}
+
+// UPDATES
+/// Returns false if the update has already been processed before
+pub fn mark_update_processed(processed_id: i32, conn: &PgConnection) -> QueryResult<bool> {
+    let inserted_count = diesel::insert_into(processed_updates)
+        .values(update_id.eq(processed_id))
+        .on_conflict_do_nothing()
+        .execute(conn)?;
+    Ok(inserted_count == 1)
+}
+
+// Telegram gives up re-delivering an update after a day, so older ones can be forgotten
+pub fn delete_processed_updates_before(
+    processed_before: DateTime<Utc>,
+    conn: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::delete(processed_updates.filter(processed_at.lt(processed_before))).execute(conn)
 }
 
 // PAYMENTS
-pub fn save_payment(new_payment: models::NewPayment, conn: &PgConnection) -> models::Payment {
+/// Returns None if a payment with the same receipt_identifier (charge id) already exists
+pub fn save_payment(
+    new_payment: models::NewPayment,
+    conn: &PgConnection,
+) -> QueryResult<Option<models::Payment>> {
     diesel::insert_into(payments)
         .values(new_payment)
+        .on_conflict(receipt_identifier)
+        .do_nothing()
         .get_result(conn)
-        .expect("Could not save new payment")
+        .optional()
 }
 
 pub fn save_transfer_id(
     payment_id: i32,
     successful_transfer_id: &str,
     conn: &PgConnection,
-) -> models::Payment {
+) -> QueryResult<models::Payment> {
     diesel::update(payments.filter(pay_id.eq(payment_id)))
         .set(transfer_id.eq(successful_transfer_id))
         .get_result(conn)
-        .expect("Could not update payment with transfer_id")
+}
+
+pub fn get_payment_by_id(payment_id: i32, conn: &PgConnection) -> QueryResult<models::Payment> {
+    payments.find(payment_id).first(conn)
+}
+
+/// Locks the payment until the end of the transaction, so nobody else changes it in between
+pub fn get_payment_for_update(
+    payment_id: i32,
+    conn: &PgConnection,
+) -> QueryResult<models::Payment> {
+    payments.find(payment_id).for_update().first(conn)
+}
+
+pub fn get_payment_by_receipt(receipt: &str, conn: &PgConnection) -> QueryResult<models::Payment> {
+    payments.filter(receipt_identifier.eq(receipt)).first(conn)
+}
+
+/// Only transitions allowed by PaymentStatus::can_transition_to are executed.
+/// The update is conditional on the status that has been checked, so concurrent updates
+/// (e.g. Stripe webhook and transfer) can not skip the check.
+pub fn update_payment_status(
+    payment_id: i32,
+    new_status: models::PaymentStatus,
+    conn: &PgConnection,
+) -> BotResult<models::Payment> {
+    let payment = get_payment_by_id(payment_id, conn)?;
+    if !payment.status.can_transition_to(new_status) {
+        return Err(BotError::InvalidStatusTransition(
+            payment.status,
+            new_status,
+        ));
+    }
+    diesel::update(
+        payments
+            .filter(pay_id.eq(payment_id))
+            .filter(pay_status.eq(payment.status)),
+    )
+    .set((pay_status.eq(new_status), status_changed_at.eq(Utc::now())))
+    .get_result(conn)
+    .map_err(BotError::from)
+}
+
+pub fn get_payments_by_status(
+    wanted_status: models::PaymentStatus,
+    conn: &PgConnection,
+) -> QueryResult<Vec<models::Payment>> {
+    payments
+        .filter(pay_status.eq(wanted_status))
+        .order(status_changed_at)
+        .load(conn)
+}
+
+/// Failed transfers (and payments, that have been received or whose transfer has been started
+/// a while ago but were never transfered) which have attempts left and whose backoff is over.
+pub fn get_payments_due_for_retry(
+    now: DateTime<Utc>,
+    unfinished_before: DateTime<Utc>,
+    max_attempts: i16,
+    conn: &PgConnection,
+) -> QueryResult<Vec<models::Payment>> {
+    payments
+        .filter(transfer_id.is_null())
+        .filter(
+            pay_status
+                .eq(models::PaymentStatus::TransferFailed)
+                .or(pay_status
+                    .eq_any(vec![
+                        models::PaymentStatus::Received,
+                        models::PaymentStatus::TransferPending,
+                    ])
+                    .and(status_changed_at.lt(unfinished_before))),
+        )
+        .filter(transfer_attempts.lt(max_attempts))
+        .filter(next_attempt_at.is_null().or(next_attempt_at.le(now)))
+        .order(pay_id)
+        .load(conn)
+}
+
+pub fn record_transfer_attempt(
+    payment_id: i32,
+    error: Option<&str>,
+    next_attempt: Option<DateTime<Utc>>,
+    conn: &PgConnection,
+) -> QueryResult<models::Payment> {
+    diesel::update(payments.filter(pay_id.eq(payment_id)))
+        .set((
+            transfer_attempts.eq(transfer_attempts + 1),
+            last_error.eq(error),
+            next_attempt_at.eq(next_attempt),
+        ))
+        .get_result(conn)
+}
+
+// The latest payment of the user, that has not been refunded
+pub fn get_last_payment_of_user(
+    payer: i32,
+    conn: &PgConnection,
+) -> QueryResult<Option<models::Payment>> {
+    payments
+        .filter(pay_user_id.eq(payer))
+        .filter(pay_status.ne(models::PaymentStatus::Refunded))
+        .order(payed_at.desc())
+        .first(conn)
+        .optional()
+}
+
+/// The fee and its details are saved together, so a payment either has both or none
+pub fn save_fee(
+    payment_id: i32,
+    actual_fee: PgMoney,
+    fee_details: Vec<models::NewPaymentFee>,
+    conn: &PgConnection,
+) -> QueryResult<models::Payment> {
+    conn.transaction(|| {
+        diesel::insert_into(payment_fees)
+            .values(&fee_details)
+            .execute(conn)?;
+        diesel::update(payments.filter(pay_id.eq(payment_id)))
+            .set(pay_fee.eq(actual_fee))
+            .get_result(conn)
+    })
+}
+
+// Newest first
+pub fn get_payments_with_fee(conn: &PgConnection) -> QueryResult<Vec<models::Payment>> {
+    payments
+        .filter(pay_fee.is_not_null())
+        .order(payed_at.desc())
+        .load(conn)
+}
+
+// Payments, the user has payed the fee of, that have not been refunded
+pub fn get_fee_paying_payments_of_user(
+    payer: i32,
+    conn: &PgConnection,
+) -> QueryResult<Vec<models::Payment>> {
+    payments
+        .filter(pay_user_id.eq(payer))
+        .filter(donor_paid_fee.eq(true))
+        .filter(pay_status.ne(models::PaymentStatus::Refunded))
+        .load(conn)
+}
+
+// Sums up to what has been spent since, refunded payments do not count
+pub fn get_payments_since(
+    payer: i32,
+    since: DateTime<Utc>,
+    conn: &PgConnection,
+) -> QueryResult<Vec<models::Payment>> {
+    payments
+        .filter(pay_user_id.eq(payer))
+        .filter(payed_at.gt(since))
+        .filter(pay_status.ne(models::PaymentStatus::Refunded))
+        .load(conn)
+}
+
+// REFUNDS
+pub fn save_refund(
+    new_refund: models::NewRefund,
+    conn: &PgConnection,
+) -> QueryResult<models::Refund> {
+    diesel::insert_into(refunds)
+        .values(new_refund)
+        .get_result(conn)
+}
+
+// SPENDING LIMITS
+// The global default and the limits of the user (if there are any)
+pub fn get_spending_limits(
+    user_id: i32,
+    conn: &PgConnection,
+) -> QueryResult<Vec<models::SpendingLimit>> {
+    spending_limits
+        .filter(limit_user_id.eq(user_id).or(limit_user_id.is_null()))
+        .load(conn)
+}
+
+// DRINKS
+pub fn get_active_drinks(conn: &PgConnection) -> QueryResult<Vec<models::Drink>> {
+    drinks.filter(active.eq(true)).order(drinks_id).load(conn)
+}
+
+pub fn get_drink_by_id(given_id: i32, conn: &PgConnection) -> QueryResult<models::Drink> {
+    drinks.find(given_id).first(conn)
+}
+
+// ORDERS
+pub fn save_order(new_order: models::NewOrder, conn: &PgConnection) -> QueryResult<models::Order> {
+    diesel::insert_into(orders)
+        .values(new_order)
+        .get_result(conn)
+}
+
+// All or none of the orders are saved (e.g. a round)
+pub fn save_orders(
+    new_orders: &[models::NewOrder],
+    conn: &PgConnection,
+) -> QueryResult<Vec<models::Order>> {
+    diesel::insert_into(orders)
+        .values(new_orders)
+        .get_results(conn)
+}
+
+// Open orders are the ones, that have not been payed yet
+pub fn get_open_orders(
+    order_owner: i32,
+    tab_chat: i64,
+    conn: &PgConnection,
+) -> QueryResult<Vec<(models::Order, models::Drink)>> {
+    orders
+        .inner_join(drinks)
+        .filter(order_user_id.eq(order_owner))
+        .filter(order_chat_id.eq(tab_chat))
+        .filter(order_payment_id.is_null())
+        .order(ordered_at)
+        .load(conn)
+}
+
+// What the user has ordered, but not yet payed, in all chats
+pub fn get_open_damage_of_user(order_owner: i32, conn: &PgConnection) -> QueryResult<i64> {
+    let prices = orders
+        .select(order_price)
+        .filter(order_user_id.eq(order_owner))
+        .filter(order_payment_id.is_null())
+        .load::<PgMoney>(conn)?;
+    Ok(prices.iter().map(|price| price.0).sum())
+}
+
+// The open orders of all members of a chat
+pub fn get_open_orders_of_chat(
+    tab_chat: i64,
+    conn: &PgConnection,
+) -> QueryResult<Vec<(models::Order, models::Drink, models::User)>> {
+    orders
+        .inner_join(drinks)
+        .inner_join(users)
+        .filter(order_chat_id.eq(tab_chat))
+        .filter(order_payment_id.is_null())
+        .order((first_name, order_user_id, ordered_at))
+        .load(conn)
+}
+
+pub fn get_last_open_order(
+    order_owner: i32,
+    tab_chat: i64,
+    conn: &PgConnection,
+) -> QueryResult<Option<(models::Order, models::Drink)>> {
+    orders
+        .inner_join(drinks)
+        .filter(order_user_id.eq(order_owner))
+        .filter(order_chat_id.eq(tab_chat))
+        .filter(order_payment_id.is_null())
+        // Orders of a round have the same ordered_at, the id tells which has been saved last
+        .order((ordered_at.desc(), orders_id.desc()))
+        .first(conn)
+        .optional()
+}
+
+pub fn delete_order(order: &models::Order, conn: &PgConnection) -> QueryResult<usize> {
+    diesel::delete(order).execute(conn)
+}
+
+pub fn count_open_orders_of_drink(
+    order_owner: i32,
+    ordered_drink: i32,
+    tab_chat: i64,
+    conn: &PgConnection,
+) -> QueryResult<i64> {
+    orders
+        .filter(order_user_id.eq(order_owner))
+        .filter(order_drink_id.eq(ordered_drink))
+        .filter(order_chat_id.eq(tab_chat))
+        .filter(order_payment_id.is_null())
+        .count()
+        .get_result(conn)
+}
+
+pub fn delete_open_orders(
+    order_owner: i32,
+    tab_chat: i64,
+    conn: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::delete(
+        orders
+            .filter(order_user_id.eq(order_owner))
+            .filter(order_chat_id.eq(tab_chat))
+            .filter(order_payment_id.is_null()),
+    )
+    .execute(conn)
+}
+
+// A payment settles the tab of the chat, the invoice has been sent to
+pub fn settle_open_orders(
+    order_owner: i32,
+    tab_chat: i64,
+    settling_payment: i32,
+    conn: &PgConnection,
+) -> QueryResult<usize> {
+    diesel::update(
+        orders
+            .filter(order_user_id.eq(order_owner))
+            .filter(order_chat_id.eq(tab_chat))
+            .filter(order_payment_id.is_null()),
+    )
+    .set(order_payment_id.eq(settling_payment))
+    .execute(conn)
+}
+
+// GROUP MEMBERS
+pub fn save_group_member(member: &models::GroupMember, conn: &PgConnection) -> QueryResult<usize> {
+    diesel::insert_into(group_members)
+        .values(member)
+        .on_conflict((member_chat_id, member_user_id))
+        .do_update()
+        .set(last_seen_at.eq(member.last_seen_at))
+        .execute(conn)
+}
+
+// Members, that have been seen in the group since the given time
+pub fn get_present_members(
+    group_chat: i64,
+    seen_since: DateTime<Utc>,
+    conn: &PgConnection,
+) -> QueryResult<Vec<models::User>> {
+    group_members
+        .inner_join(users)
+        .filter(member_chat_id.eq(group_chat))
+        .filter(last_seen_at.ge(seen_since))
+        .order(first_name)
+        .select(user_columns)
+        .load(conn)
+}
+
+// PUBS
+pub fn get_active_pubs(conn: &PgConnection) -> QueryResult<Vec<models::Pub>> {
+    pubs.filter(pub_active.eq(true)).order(pubs_id).load(conn)
+}
+
+pub fn get_pub_by_id(given_id: i32, conn: &PgConnection) -> QueryResult<models::Pub> {
+    pubs.find(given_id).first(conn)
 }
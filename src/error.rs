@@ -0,0 +1,83 @@
+use crate::models::PaymentStatus;
+use rocket::http::{ContentType, Status};
+use rocket::request::Request;
+use rocket::response::{self, Responder, Response};
+use std::fmt;
+use std::io::Cursor;
+
+pub type BotResult<T> = Result<T, BotError>;
+
+/// All errors that can happen while handling an update or a payment
+#[derive(Debug)]
+pub enum BotError {
+    // The update (or the payload it carries) is not what we expect from Telegram
+    TelegramParse(String),
+    // A call to the Telegram Bot API failed
+    Telegram(String),
+    Db(diesel::result::Error),
+    Stripe(String),
+    // Missing or invalid settings (e.g. env-variables)
+    Config(String),
+    InvalidStatusTransition(PaymentStatus, PaymentStatus),
+}
+
+impl BotError {
+    pub fn status(&self) -> Status {
+        match self {
+            BotError::TelegramParse(_) => Status::BadRequest,
+            _ => Status::InternalServerError,
+        }
+    }
+}
+
+impl fmt::Display for BotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BotError::TelegramParse(e) => write!(f, "Could not parse Telegram data: {}", e),
+            BotError::Telegram(e) => write!(f, "Telegram error: {}", e),
+            BotError::Db(e) => write!(f, "Database error: {}", e),
+            BotError::Stripe(e) => write!(f, "Stripe error: {}", e),
+            BotError::Config(e) => write!(f, "Configuration error: {}", e),
+            BotError::InvalidStatusTransition(current, next) => write!(
+                f,
+                "Payment status can not change from {} to {}",
+                current, next
+            ),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+impl From<diesel::result::Error> for BotError {
+    fn from(e: diesel::result::Error) -> Self {
+        BotError::Db(e)
+    }
+}
+
+impl From<reqwest::Error> for BotError {
+    fn from(e: reqwest::Error) -> Self {
+        BotError::Stripe(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for BotError {
+    fn from(e: serde_json::Error) -> Self {
+        BotError::TelegramParse(e.to_string())
+    }
+}
+
+/// Errors that can not be answered in the chat end up as HTTP-response.
+/// The details are only logged, the response body just states the status.
+impl<'r> Responder<'r> for BotError {
+    fn respond_to(self, _request: &Request) -> response::Result<'r> {
+        eprintln!("Request failed. Err: {}", self);
+        let status = self.status();
+        let body = serde_json::json!({ "error": status.reason }).to_string();
+        Response::build()
+            .status(status)
+            .header(ContentType::JSON)
+            .sized_body(Cursor::new(body))
+            .ok()
+    }
+}
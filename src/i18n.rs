@@ -0,0 +1,174 @@
+use crate::error::{BotError, BotResult};
+use crate::models;
+use crate::payments::money_in_eur;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::fmt;
+
+// The catalogs are compiled into the binary, so a deployment can not miss them
+static DE_CATALOG: &str = include_str!("../locales/de.toml");
+static EN_CATALOG: &str = include_str!("../locales/en.toml");
+
+/// Languages the bot speaks. Stored by its code in users.language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Language {
+    De,
+    En,
+}
+
+impl Language {
+    pub const ALL: [Language; 2] = [Language::De, Language::En];
+    // Users created before languages were introduced speak German
+    pub const DEFAULT: Language = Language::De;
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Language::De => "de",
+            Language::En => "en",
+        }
+    }
+
+    // Also accepts IETF tags like Telegram's language_code (e.g. "de-AT")
+    pub fn from_code(code: &str) -> Option<Language> {
+        let primary = code.split('-').next().unwrap_or("").to_lowercase();
+        Language::ALL
+            .iter()
+            .copied()
+            .find(|language| language.code() == primary)
+    }
+
+    // Telegram only sends the language_code if the user has set one
+    pub fn from_telegram(language_code: Option<&str>) -> Language {
+        match language_code {
+            Some(code) => Language::from_code(code).unwrap_or(Language::En),
+            None => Language::DEFAULT,
+        }
+    }
+
+    pub fn of_user(user: &models::User) -> Language {
+        user.language
+            .as_ref()
+            .and_then(|code| Language::from_code(code))
+            .unwrap_or(Language::DEFAULT)
+    }
+
+    // Shown on the buttons, so it is always written in the language itself
+    pub fn name(&self) -> &'static str {
+        match self {
+            Language::De => "🇩🇪 Deutsch",
+            Language::En => "🇬🇧 English",
+        }
+    }
+
+    fn catalog(&self) -> &'static str {
+        match self {
+            Language::De => DE_CATALOG,
+            Language::En => EN_CATALOG,
+        }
+    }
+}
+
+impl fmt::Display for Language {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+// Every catalog is parsed once, when the first texts are needed
+static CATALOGS: Lazy<HashMap<Language, HashMap<String, String>>> = Lazy::new(|| {
+    Language::ALL
+        .iter()
+        .map(|&language| {
+            // The catalogs are checked at startup (see validate), so this should never fail
+            let entries = parse_catalog(language).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                HashMap::new()
+            });
+            (language, entries)
+        })
+        .collect()
+});
+
+/// All texts of one language.
+/// Placeholders in curly braces (e.g. {pub}) are replaced with format.
+pub struct Texts {
+    language: Language,
+    entries: &'static HashMap<String, String>,
+}
+
+impl Texts {
+    pub fn new(language: Language) -> Texts {
+        Texts {
+            language,
+            entries: &CATALOGS[&language],
+        }
+    }
+
+    pub fn language(&self) -> Language {
+        self.language
+    }
+
+    // A missing text shows its key instead of failing the whole answer
+    pub fn get(&self, key: &str) -> String {
+        match self.entries.get(key) {
+            Some(text) => text.to_string(),
+            None => {
+                eprintln!("Text '{}' is missing in language {}", key, self.language);
+                key.to_string()
+            }
+        }
+    }
+
+    pub fn format(&self, key: &str, args: &[(&str, &dyn fmt::Display)]) -> String {
+        args.iter().fold(self.get(key), |text, (name, value)| {
+            text.replace(&format!("{{{}}}", name), &value.to_string())
+        })
+    }
+
+    // Drinks are named in the language of the catalog (drink_<name>, e.g. drink_bier).
+    // Drinks without a translation keep the name they have in the database.
+    pub fn drink_name(&self, drink: &models::Drink) -> String {
+        let key = format!("drink_{}", drink.name.to_lowercase());
+        match self.entries.get(&key) {
+            Some(name) => name.to_string(),
+            None => drink.name.to_string(),
+        }
+    }
+
+    // Cents as euros with two decimals, but without the €-sign (1,50 or 1.50)
+    pub fn money(&self, cents: i64) -> String {
+        format!("{:.2}", money_in_eur(cents)).replace(".", &self.get("decimal_separator"))
+    }
+}
+
+fn parse_catalog(language: Language) -> BotResult<HashMap<String, String>> {
+    toml::from_str(language.catalog()).map_err(|e| {
+        BotError::Config(format!(
+            "Invalid text catalog of language {}: {}",
+            language, e
+        ))
+    })
+}
+
+/// Every catalog must parse and contain the same keys as the default language.
+pub fn validate() -> BotResult<()> {
+    let default_keys = parse_catalog(Language::DEFAULT)?;
+    for language in Language::ALL.iter() {
+        let entries = parse_catalog(*language)?;
+        let missing: Vec<&String> = default_keys
+            .keys()
+            .filter(|key| !entries.contains_key(*key))
+            .collect();
+        let unknown: Vec<&String> = entries
+            .keys()
+            .filter(|key| !default_keys.contains_key(*key))
+            .collect();
+        if !missing.is_empty() || !unknown.is_empty() {
+            return Err(BotError::Config(format!(
+                "Text catalog of language {} is missing {:?} and has unknown {:?}",
+                language, missing, unknown
+            )));
+        }
+    }
+    Ok(())
+}
@@ -6,31 +6,21 @@ extern crate rocket;
 
 pub mod bot_context;
 pub mod bot_types;
+pub mod config;
+pub mod conversation;
 pub mod db;
-pub mod messages;
+pub mod error;
+pub mod i18n;
+pub mod limits;
 pub mod models;
 pub mod payments;
+pub mod refunds;
+pub mod reports;
 pub mod schema;
+pub mod stripe_events;
 pub mod stripe_types;
+pub mod telegram_client;
 pub mod telegram_types;
-
-pub fn get_args() -> Vec<String> {
-    std::env::args().collect()
-}
-
-pub fn get_ngrok_url() -> Option<String> {
-    let args = get_args();
-    match args.len() {
-        3 | 4 if args[1] == "url" => Some(args[2].to_string()),
-        _ => None,
-    }
-}
-
-pub fn is_test() -> bool {
-    let args = get_args();
-    match args.len() {
-        4 if args[3] == "test" => true,
-        2 if args[1] == "test" => true,
-        _ => false,
-    }
-}
+pub mod transfer_worker;
+pub mod update_handler;
+pub mod update_poller;
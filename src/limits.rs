@@ -0,0 +1,266 @@
+use crate::db;
+use crate::error::BotResult;
+use crate::i18n::Texts;
+use crate::models::SpendingLimit;
+use chrono::{DateTime, Duration, Utc};
+use rocket_contrib::databases::diesel::PgConnection;
+
+/// Payments are summed up over the last day, 7 days or 30 days (not calendar periods)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+
+impl Period {
+    pub const ALL: [Period; 3] = [Period::Day, Period::Week, Period::Month];
+
+    pub fn duration(&self) -> Duration {
+        match self {
+            Period::Day => Duration::days(1),
+            Period::Week => Duration::days(7),
+            Period::Month => Duration::days(30),
+        }
+    }
+}
+
+/// The limit, that blocks an order or a payment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    // The tab would be higher than the limit
+    Tab {
+        limit: i64,
+        tab: i64,
+    },
+    Invoice {
+        limit: i64,
+        total: i64,
+    },
+    // What has been payed within the period, the new invoice not included
+    Period {
+        period: Period,
+        limit: i64,
+        spent: i64,
+    },
+}
+
+impl LimitExceeded {
+    pub fn text(&self, texts: &Texts) -> String {
+        match *self {
+            LimitExceeded::Tab { limit, tab } => texts.format(
+                "limit_tab",
+                &[
+                    ("damage", &texts.money(tab)),
+                    ("limit", &texts.money(limit)),
+                ],
+            ),
+            LimitExceeded::Invoice { limit, total } => texts.format(
+                "limit_invoice",
+                &[
+                    ("amount", &texts.money(total)),
+                    ("limit", &texts.money(limit)),
+                ],
+            ),
+            LimitExceeded::Period {
+                period,
+                limit,
+                spent,
+            } => {
+                let key = match period {
+                    Period::Day => "limit_daily",
+                    Period::Week => "limit_weekly",
+                    Period::Month => "limit_monthly",
+                };
+                texts.format(
+                    key,
+                    &[
+                        ("spent", &texts.money(spent)),
+                        ("limit", &texts.money(limit)),
+                    ],
+                )
+            }
+        }
+    }
+}
+
+/// The spending limits of a user in cents, a limit of None is not enforced.
+/// Every limit of the global default can be overridden for a single user (see spending_limits).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub tab: Option<i64>,
+    pub invoice: Option<i64>,
+    pub daily: Option<i64>,
+    pub weekly: Option<i64>,
+    pub monthly: Option<i64>,
+}
+
+impl Limits {
+    pub fn of_user(user_id: i32, conn: &PgConnection) -> BotResult<Limits> {
+        Ok(Limits::merge(db::get_spending_limits(user_id, conn)?))
+    }
+
+    /// Merges the global default with the limits of the user, field by field
+    pub fn merge(mut spending_limits: Vec<SpendingLimit>) -> Limits {
+        // The global default first, so that the limits of the user win
+        spending_limits.sort_by_key(|spending_limit| spending_limit.user_id.is_some());
+        let mut limits = Limits::default();
+        for spending_limit in spending_limits {
+            limits.tab = spending_limit.max_tab.map(|money| money.0).or(limits.tab);
+            limits.invoice = spending_limit
+                .max_invoice
+                .map(|money| money.0)
+                .or(limits.invoice);
+            limits.daily = spending_limit
+                .max_daily
+                .map(|money| money.0)
+                .or(limits.daily);
+            limits.weekly = spending_limit
+                .max_weekly
+                .map(|money| money.0)
+                .or(limits.weekly);
+            limits.monthly = spending_limit
+                .max_monthly
+                .map(|money| money.0)
+                .or(limits.monthly);
+        }
+        limits
+    }
+
+    fn of_period(&self, period: Period) -> Option<i64> {
+        match period {
+            Period::Day => self.daily,
+            Period::Week => self.weekly,
+            Period::Month => self.monthly,
+        }
+    }
+
+    /// An order must leave a tab, that can still be payed with one invoice.
+    /// The limit of the tab applies to the open orders of all chats (open_damage),
+    /// an invoice only pays the tab of one chat.
+    pub fn check_order(
+        &self,
+        tab: i64,
+        open_damage: i64,
+        user_id: i32,
+        now: DateTime<Utc>,
+        conn: &PgConnection,
+    ) -> BotResult<Option<LimitExceeded>> {
+        if let Some(limit) = self.tab {
+            if open_damage > limit {
+                return Ok(Some(LimitExceeded::Tab {
+                    limit,
+                    tab: open_damage,
+                }));
+            }
+        }
+        self.check_invoice(tab, user_id, now, conn)
+    }
+
+    /// All limits apply to the tab, a fee the donor pays on top does not count
+    pub fn check_invoice(
+        &self,
+        total: i64,
+        user_id: i32,
+        now: DateTime<Utc>,
+        conn: &PgConnection,
+    ) -> BotResult<Option<LimitExceeded>> {
+        if let Some(limit) = self.invoice {
+            if total > limit {
+                return Ok(Some(LimitExceeded::Invoice { limit, total }));
+            }
+        }
+        for &period in Period::ALL.iter() {
+            if let Some(limit) = self.of_period(period) {
+                let since = now - period.duration();
+                let spent: i64 = db::get_payments_since(user_id, since, conn)?
+                    .iter()
+                    .map(|payment| payment.payed_amount.0 - payment.donor_fee())
+                    .sum();
+                if spent + total > limit {
+                    return Ok(Some(LimitExceeded::Period {
+                        period,
+                        limit,
+                        spent,
+                    }));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use diesel::data_types::PgMoney;
+
+    fn spending_limit(user_id: Option<i32>, limits: [Option<i64>; 5]) -> SpendingLimit {
+        SpendingLimit {
+            id: user_id.unwrap_or(0),
+            user_id,
+            max_tab: limits[0].map(PgMoney),
+            max_invoice: limits[1].map(PgMoney),
+            max_daily: limits[2].map(PgMoney),
+            max_weekly: limits[3].map(PgMoney),
+            max_monthly: limits[4].map(PgMoney),
+        }
+    }
+
+    #[test]
+    fn nothing_is_enforced_without_limits() {
+        assert_eq!(Limits::merge(Vec::new()), Limits::default());
+    }
+
+    #[test]
+    fn the_default_applies_to_everybody() {
+        let default = spending_limit(None, [Some(5000), Some(10000), None, Some(20000), None]);
+        assert_eq!(
+            Limits::merge(vec![default]),
+            Limits {
+                tab: Some(5000),
+                invoice: Some(10000),
+                daily: None,
+                weekly: Some(20000),
+                monthly: None,
+            }
+        );
+    }
+
+    #[test]
+    fn the_user_overrides_the_default_per_limit() {
+        let default = || spending_limit(None, [Some(5000), Some(10000), None, Some(20000), None]);
+        let user = || spending_limit(Some(7), [Some(2000), None, Some(3000), None, None]);
+        let expected = Limits {
+            tab: Some(2000),
+            invoice: Some(10000),
+            daily: Some(3000),
+            weekly: Some(20000),
+            monthly: None,
+        };
+        assert_eq!(Limits::merge(vec![default(), user()]), expected);
+        // The order of the rows does not matter
+        assert_eq!(Limits::merge(vec![user(), default()]), expected);
+    }
+
+    #[test]
+    fn users_can_have_higher_limits_than_the_default() {
+        let default = spending_limit(None, [Some(5000), None, None, None, Some(30000)]);
+        let user = spending_limit(Some(7), [Some(15000), None, None, None, Some(90000)]);
+        let limits = Limits::merge(vec![user, default]);
+        assert_eq!(limits.tab, Some(15000));
+        assert_eq!(limits.monthly, Some(90000));
+    }
+
+    #[test]
+    fn a_user_without_default_only_has_their_own_limits() {
+        let user = spending_limit(Some(7), [None, Some(4000), None, None, None]);
+        assert_eq!(
+            Limits::merge(vec![user]),
+            Limits {
+                invoice: Some(4000),
+                ..Limits::default()
+            }
+        );
+    }
+}
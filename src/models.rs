@@ -1,6 +1,13 @@
-use crate::schema::{payments, users};
-use diesel::data_types::{PgMoney, PgTimestamp};
+use crate::schema::{conversations, group_members, orders, payment_fees, payments, refunds, users};
+use chrono::{DateTime, Utc};
+use diesel::data_types::PgMoney;
+use diesel::deserialize::{self, FromSql};
+use diesel::pg::Pg;
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::sql_types::Varchar;
 use diesel::{Identifiable, Insertable, Queryable};
+use std::fmt;
+use std::io::Write;
 // Order must be the same as the columns (http://diesel.rs/guides/getting-started/)
 #[derive(Debug, Queryable, Identifiable)]
 pub struct User {
@@ -8,11 +15,21 @@ pub struct User {
     pub name: String,
     pub first_name: String,
     pub last_name: String,
-    pub drink_count: i16,
-    pub price: PgMoney,
-    pub last_paid: PgTimestamp,
+    // If set, overrides the catalog price of every drink
+    pub price: Option<PgMoney>,
+    pub last_paid: DateTime<Utc>,
     pub last_total: PgMoney,
     pub total: PgMoney,
+    // The pub the user donates to
+    pub pub_id: Option<i32>,
+    // False once the user has blocked the bot
+    pub active: bool,
+    // Dates are shown in this timezone (if not set, in the one of the pub)
+    pub timezone: Option<String>,
+    // Code of the language the bot speaks with the user (e.g. "de")
+    pub language: Option<String>,
+    // If true, the Stripe fee is added to the invoice instead of being taken from the donation
+    pub donor_pays_fee: bool,
 }
 
 #[derive(Debug, Insertable)]
@@ -22,26 +39,87 @@ pub struct NewUser<'a> {
     pub name: &'a str,
     pub first_name: &'a str,
     pub last_name: &'a str,
+    pub language: Option<&'a str>,
 }
 
 #[derive(Debug, AsChangeset, Default)]
 #[table_name = "users"]
 pub struct UpdateUser {
-    pub drink_count: Option<i16>,
-    pub price: Option<PgMoney>,
-    pub last_paid: Option<PgTimestamp>,
+    pub price: Option<Option<PgMoney>>,
+    pub last_paid: Option<DateTime<Utc>>,
     pub last_total: Option<PgMoney>,
     pub total: Option<PgMoney>,
+    pub pub_id: Option<Option<i32>>,
+    pub active: Option<bool>,
+    pub language: Option<Option<String>>,
+    pub donor_pays_fee: Option<bool>,
+}
+
+// One per user and chat, a user without conversation is idle
+#[derive(Debug, Queryable, Identifiable)]
+#[primary_key(user_id, chat_id)]
+pub struct Conversation {
+    pub user_id: i32,
+    pub chat_id: i64,
+    pub state: ConversationState,
+    pub state_changed_at: DateTime<Utc>,
 }
 
-impl UpdateUser {
-    pub fn from_user(user: &User) -> UpdateUser {
-        UpdateUser {
-            drink_count: Some(user.drink_count),
-            price: Some(user.price),
-            last_paid: Some(user.last_paid),
-            last_total: Some(user.last_total),
-            total: Some(user.last_total),
+#[derive(Debug, Insertable, AsChangeset)]
+#[table_name = "conversations"]
+pub struct NewConversation {
+    pub user_id: i32,
+    pub chat_id: i64,
+    pub state: ConversationState,
+    pub state_changed_at: DateTime<Utc>,
+}
+
+// Stored as VARCHAR in conversations.state
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow)]
+#[sql_type = "Varchar"]
+pub enum ConversationState {
+    // Messages are read as commands
+    Idle,
+    // The next message is read as the new drink price
+    AwaitingPrice,
+    // The bill has been shown, waiting for pay, not yet or steal
+    ConfirmingPayment,
+    // Waiting for the user to confirm (or cancel) the deletion of the data
+    ConfirmingDelete,
+}
+
+impl ConversationState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConversationState::Idle => "idle",
+            ConversationState::AwaitingPrice => "awaiting_price",
+            ConversationState::ConfirmingPayment => "confirming_payment",
+            ConversationState::ConfirmingDelete => "confirming_delete",
+        }
+    }
+}
+
+impl fmt::Display for ConversationState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ToSql<Varchar, Pg> for ConversationState {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for ConversationState {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"idle" => Ok(ConversationState::Idle),
+            b"awaiting_price" => Ok(ConversationState::AwaitingPrice),
+            b"confirming_payment" => Ok(ConversationState::ConfirmingPayment),
+            b"confirming_delete" => Ok(ConversationState::ConfirmingDelete),
+            _ => Err("Unknown conversation state".into()),
         }
     }
 }
@@ -52,8 +130,110 @@ pub struct Payment {
     pub user_id: i32,
     pub receipt_identifier: String,
     pub payed_amount: PgMoney,
-    pub payed_at: PgTimestamp,
+    pub payed_at: DateTime<Utc>,
     pub transfer_id: Option<String>,
+    pub status: PaymentStatus,
+    pub status_changed_at: DateTime<Utc>,
+    // The pub, the payment is transfered to
+    pub pub_id: Option<i32>,
+    pub transfer_attempts: i16,
+    pub last_error: Option<String>,
+    // A failed transfer is not retried before this point in time
+    pub next_attempt_at: Option<DateTime<Utc>>,
+    // Shown on the invoice
+    pub estimated_fee: Option<PgMoney>,
+    // Withheld by Stripe, is known once the charge has been looked up for the transfer
+    pub fee: Option<PgMoney>,
+    // The fee has been added to the payed_amount
+    pub donor_paid_fee: bool,
+}
+
+impl Payment {
+    // What the donor has payed on top of the tab (the estimate shown on the invoice)
+    pub fn donor_fee(&self) -> i64 {
+        match (self.donor_paid_fee, self.estimated_fee) {
+            (true, Some(estimated_fee)) => estimated_fee.0,
+            _ => 0,
+        }
+    }
+}
+
+// Stored as VARCHAR in payments.status
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsExpression, FromSqlRow)]
+#[sql_type = "Varchar"]
+pub enum PaymentStatus {
+    // The user has payed (money is on our Stripe account)
+    Received,
+    // The transfer to the pub has been started
+    TransferPending,
+    Transferred,
+    TransferFailed,
+    // An admin has started the refund, Stripe has not confirmed it yet
+    RefundPending,
+    Refunded,
+}
+
+impl PaymentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PaymentStatus::Received => "received",
+            PaymentStatus::TransferPending => "transfer_pending",
+            PaymentStatus::Transferred => "transferred",
+            PaymentStatus::TransferFailed => "transfer_failed",
+            PaymentStatus::RefundPending => "refund_pending",
+            PaymentStatus::Refunded => "refunded",
+        }
+    }
+
+    // Setting the current status again is allowed, because Stripe may report things twice
+    pub fn can_transition_to(&self, next: PaymentStatus) -> bool {
+        use PaymentStatus::*;
+        match (self, next) {
+            (current, next) if *current == next => true,
+            (Received, TransferPending) => true,
+            // The transfer could not even be started
+            (Received, TransferFailed) => true,
+            (TransferPending, Transferred) | (TransferPending, TransferFailed) => true,
+            // A failed transfer can be retried
+            (TransferFailed, TransferPending) => true,
+            // A running transfer has to finish (or fail) before a refund
+            (Received, RefundPending) | (TransferFailed, RefundPending) => true,
+            (Transferred, RefundPending) => true,
+            (RefundPending, Refunded) => true,
+            // Stripe reports refunds, that have been made in the dashboard, as well
+            (Received, Refunded) | (TransferFailed, Refunded) | (Transferred, Refunded) => true,
+            // The transfer has been reversed (e.g. in the Stripe dashboard)
+            (Transferred, TransferFailed) => true,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for PaymentStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl ToSql<Varchar, Pg> for PaymentStatus {
+    fn to_sql<W: Write>(&self, out: &mut Output<W, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<Varchar, Pg> for PaymentStatus {
+    fn from_sql(bytes: Option<&[u8]>) -> deserialize::Result<Self> {
+        match not_none!(bytes) {
+            b"received" => Ok(PaymentStatus::Received),
+            b"transfer_pending" => Ok(PaymentStatus::TransferPending),
+            b"transferred" => Ok(PaymentStatus::Transferred),
+            b"transfer_failed" => Ok(PaymentStatus::TransferFailed),
+            b"refund_pending" => Ok(PaymentStatus::RefundPending),
+            b"refunded" => Ok(PaymentStatus::Refunded),
+            _ => Err("Unknown payment status".into()),
+        }
+    }
 }
 
 #[derive(Debug, Insertable)]
@@ -62,5 +242,184 @@ pub struct NewPayment<'a> {
     pub user_id: i32,
     pub receipt_identifier: &'a str,
     pub payed_amount: PgMoney,
-    pub payed_at: PgTimestamp,
+    pub payed_at: DateTime<Utc>,
+    pub pub_id: i32,
+    pub estimated_fee: Option<PgMoney>,
+    pub donor_paid_fee: bool,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+pub struct PaymentFee {
+    pub id: i32,
+    pub payment_id: i32,
+    pub amount: PgMoney,
+    pub currency: String,
+    pub description: String,
+    // e.g. stripe_fee or application_fee
+    pub fee_type: String,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "payment_fees"]
+pub struct NewPaymentFee<'a> {
+    pub payment_id: i32,
+    pub amount: PgMoney,
+    pub currency: &'a str,
+    pub description: &'a str,
+    pub fee_type: &'a str,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+pub struct Refund {
+    pub id: i32,
+    pub payment_id: i32,
+    pub stripe_refund_id: String,
+    pub amount: PgMoney,
+    pub reason: Option<String>,
+    pub transfer_reversed: bool,
+    pub refunded_at: DateTime<Utc>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "refunds"]
+pub struct NewRefund<'a> {
+    pub payment_id: i32,
+    pub stripe_refund_id: &'a str,
+    pub amount: PgMoney,
+    pub reason: Option<&'a str>,
+    pub transfer_reversed: bool,
+    pub refunded_at: DateTime<Utc>,
+}
+
+// Limits are in cents, None is not enforced (see limits::Limits)
+#[derive(Debug, Queryable, Identifiable)]
+pub struct SpendingLimit {
+    pub id: i32,
+    // None for the global default
+    pub user_id: Option<i32>,
+    pub max_tab: Option<PgMoney>,
+    pub max_invoice: Option<PgMoney>,
+    pub max_daily: Option<PgMoney>,
+    pub max_weekly: Option<PgMoney>,
+    pub max_monthly: Option<PgMoney>,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+pub struct Drink {
+    pub id: i32,
+    pub name: String,
+    pub emoji: String,
+    pub price: PgMoney,
+    pub active: bool,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+pub struct Order {
+    pub id: i32,
+    pub user_id: i32,
+    pub drink_id: i32,
+    // The unit price at the time of the order
+    pub price: PgMoney,
+    pub ordered_at: DateTime<Utc>,
+    pub payment_id: Option<i32>,
+    // The tab is per chat (private or group)
+    pub chat_id: i64,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "orders"]
+pub struct NewOrder {
+    pub user_id: i32,
+    pub drink_id: i32,
+    pub price: PgMoney,
+    pub ordered_at: DateTime<Utc>,
+    pub chat_id: i64,
+}
+
+#[derive(Debug, Queryable, Identifiable)]
+pub struct Pub {
+    pub id: i32,
+    pub name: String,
+    // Id of the connected Stripe account, which receives the donations
+    pub stripe_account: String,
+    pub logo_url: Option<String>,
+    pub active: bool,
+    // e.g. Europe/Berlin
+    pub timezone: String,
+}
+
+#[derive(Debug, Insertable, AsChangeset)]
+#[table_name = "group_members"]
+pub struct GroupMember {
+    pub chat_id: i64,
+    pub user_id: i32,
+    // Members seen recently are present and get a drink, when a round is ordered
+    pub last_seen_at: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PaymentStatus::*;
+    use super::*;
+
+    static ALL: [PaymentStatus; 6] = [
+        Received,
+        TransferPending,
+        Transferred,
+        TransferFailed,
+        RefundPending,
+        Refunded,
+    ];
+
+    #[test]
+    fn every_status_can_be_reported_again() {
+        for status in ALL.iter() {
+            assert!(status.can_transition_to(*status), "{}", status);
+        }
+    }
+
+    #[test]
+    fn transfers_can_fail_and_be_retried() {
+        assert!(Received.can_transition_to(TransferPending));
+        assert!(Received.can_transition_to(TransferFailed));
+        assert!(TransferPending.can_transition_to(Transferred));
+        assert!(TransferPending.can_transition_to(TransferFailed));
+        assert!(TransferFailed.can_transition_to(TransferPending));
+        assert!(Transferred.can_transition_to(TransferFailed));
+    }
+
+    #[test]
+    fn refunds_are_possible_unless_a_transfer_is_running() {
+        for status in [Received, TransferFailed, Transferred].iter() {
+            assert!(status.can_transition_to(RefundPending), "{}", status);
+            assert!(status.can_transition_to(Refunded), "{}", status);
+        }
+        assert!(RefundPending.can_transition_to(Refunded));
+        assert!(!TransferPending.can_transition_to(RefundPending));
+        assert!(!TransferPending.can_transition_to(Refunded));
+    }
+
+    #[test]
+    fn refunded_is_final() {
+        for status in ALL.iter().filter(|status| **status != Refunded) {
+            assert!(!Refunded.can_transition_to(*status), "{}", status);
+        }
+    }
+
+    #[test]
+    fn pending_refunds_cannot_be_transferred() {
+        for status in [Received, TransferPending, Transferred, TransferFailed].iter() {
+            assert!(!RefundPending.can_transition_to(*status), "{}", status);
+        }
+    }
+
+    #[test]
+    fn payments_cannot_go_back() {
+        assert!(!TransferPending.can_transition_to(Received));
+        assert!(!Transferred.can_transition_to(Received));
+        assert!(!Transferred.can_transition_to(TransferPending));
+        assert!(!TransferFailed.can_transition_to(Received));
+        assert!(!TransferFailed.can_transition_to(Transferred));
+        assert!(!Received.can_transition_to(Transferred));
+    }
 }
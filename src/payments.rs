@@ -1,116 +1,281 @@
+use crate::config::Config;
 use crate::db;
-use crate::is_test;
-use crate::models::{NewPayment, Payment, UpdateUser};
+use crate::error::{BotError, BotResult};
+use crate::models::{NewPayment, NewPaymentFee, Payment, PaymentStatus, Pub, UpdateUser};
 use crate::stripe_types::*;
 use crate::telegram_types::SuccessfulPayment;
 use chrono::{Duration, Utc};
-use diesel::pg::data_types::PgTimestamp;
 use diesel::pg::types::money::PgMoney;
+use diesel::Connection;
 use reqwest::blocking::Client;
+use rocket_contrib::databases::diesel::PgConnection;
+
+// A failed transfer is retried after RETRY_BASE_DELAY minutes, then the delay doubles each attempt
+pub static MAX_TRANSFER_ATTEMPTS: i16 = 8;
+static RETRY_BASE_DELAY: i64 = 5;
+// Received (or pending) payments are left alone for this many minutes, because pay might still be
+// working on them. A transfer, that was interrupted in between, is found again by find_transfer.
+static UNFINISHED_GRACE_PERIOD: i64 = 10;
 
 pub fn pay(
     successful_payment: &SuccessfulPayment,
-    conn: db::UserDbConn,
-) -> Result<(), reqwest::Error> {
+    conn: &PgConnection,
+    config: &Config,
+) -> BotResult<()> {
     // User has successfuly payed, so this fact is saved
-    let payment = persist_payment(successful_payment, &conn);
-
-    let stripe_token_str = if is_test() {
-        "STRIPE_TOKEN_TEST"
-    } else {
-        "STRIPE_TOKEN"
+    let payment = match persist_payment(successful_payment, conn)? {
+        Some(payment) => payment,
+        None => {
+            println!(
+                "Payment with charge {} has already been processed",
+                successful_payment.provider_payment_charge_id
+            );
+            return Ok(());
+        }
     };
 
-    let stripe_token = std::env::var(stripe_token_str).unwrap();
+    // If the transfer can not be done now, the transfer worker retries it later
     let client = Client::builder().build()?;
+    attempt_transfer(&payment, &client, &config.stripe_token, conn)
+}
 
-    let balance = get_balance(&client, &stripe_token)?;
-    let pending_amount = balance.pending.first().unwrap().amount;
+/// Retries all transfers that have failed or never been started.
+/// Is called periodically by the transfer worker.
+pub fn retry_transfers(conn: &PgConnection, config: &Config) -> BotResult<()> {
+    let now = Utc::now();
+    let unfinished_before = now - Duration::minutes(UNFINISHED_GRACE_PERIOD);
+    let due_payments =
+        db::get_payments_due_for_retry(now, unfinished_before, MAX_TRANSFER_ATTEMPTS, conn)?;
+    if due_payments.is_empty() {
+        return Ok(());
+    }
 
-    let charge = get_charge_by_payment(&successful_payment, &client, &stripe_token)?;
-    let transfer_amount = charge.balance_transaction.net;
+    let client = Client::builder().build()?;
+    for payment in due_payments {
+        println!(
+            "Retrying transfer of payment {} (attempt {})",
+            payment.id,
+            payment.transfer_attempts + 1
+        );
+        if let Err(e) = attempt_transfer(&payment, &client, &config.stripe_token, conn) {
+            eprintln!("Retry of payment {} failed. Err: {}", payment.id, e);
+        }
+    }
+    Ok(())
+}
+
+/// Tries to transfer the payment to its pub and records the outcome (status, attempts, error)
+fn attempt_transfer(
+    payment: &Payment,
+    client: &Client,
+    stripe_token: &str,
+    conn: &PgConnection,
+) -> BotResult<()> {
+    let beneficiary = match payment.pub_id.map(|pub_id| db::get_pub_by_id(pub_id, conn)) {
+        Some(Ok(beneficiary)) => beneficiary,
+        _ => {
+            let error =
+                BotError::Config(format!("Payment {} has no pub to transfer to", payment.id));
+            record_failed_attempt(payment, &error, conn)?;
+            return Err(error);
+        }
+    };
+
+    set_payment_status(payment.id, PaymentStatus::TransferPending, conn);
+    let outcome = match transfer_payment(payment, &beneficiary, client, stripe_token, conn) {
+        Ok(transfer) => {
+            db::save_transfer_id(payment.id, &transfer.id, conn)?;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    };
 
-    // Check current available balance to be sure, that transfer-amount is covered
-    if pending_amount > transfer_amount {
-        let payment_intent = payment_intent_request(&client, &stripe_token, transfer_amount)?;
-        let confirm_payment = confirm_payment(&payment_intent.id, &client, &stripe_token);
-        match confirm_payment {
-            Ok(confirmed) => set_transfer_id_on_payment(payment.id, &confirmed.id, &conn),
-            Err(e) => eprintln!("Payment could not be transfered. Err: {}", e),
+    match &outcome {
+        Ok(()) => {
+            db::record_transfer_attempt(payment.id, None, None, conn)?;
+            set_payment_status(payment.id, PaymentStatus::Transferred, conn);
         }
+        Err(error) => record_failed_attempt(payment, error, conn)?,
+    }
+    outcome
+}
+
+fn record_failed_attempt(
+    payment: &Payment,
+    error: &BotError,
+    conn: &PgConnection,
+) -> BotResult<()> {
+    let next_attempt = Utc::now() + retry_delay(payment.transfer_attempts);
+    let error = error.to_string();
+    db::record_transfer_attempt(payment.id, Some(&error), Some(next_attempt), conn)?;
+    set_payment_status(payment.id, PaymentStatus::TransferFailed, conn);
+    Ok(())
+}
+
+// 5, 10, 20, 40... minutes
+fn retry_delay(previous_attempts: i16) -> Duration {
+    let exponent = previous_attempts.max(0).min(MAX_TRANSFER_ATTEMPTS) as u32;
+    Duration::minutes(RETRY_BASE_DELAY * 2_i64.pow(exponent))
+}
+
+/// Transfers the net amount of the payment's charge to the pub.
+/// The charge is the source_transaction of the transfer, so the pub gets exactly
+/// the money the user has payed (even while it is still pending).
+fn transfer_payment(
+    payment: &Payment,
+    beneficiary: &Pub,
+    client: &Client,
+    stripe_token: &str,
+    conn: &PgConnection,
+) -> BotResult<Transfer> {
+    let charge = get_charge_by_payment(&payment.receipt_identifier, client, stripe_token)?;
+    // Retries look up the same charge, the fee is only recorded once
+    if payment.fee.is_none() {
+        record_fee(payment, &charge.balance_transaction, conn);
+    }
+    let transfer_amount = charge.balance_transaction.net;
 
-        let reduced_balance = get_balance(&client, &stripe_token)?;
-        let pending_amount_reduced = reduced_balance.pending.first().unwrap().amount;
-        if pending_amount_reduced != pending_amount - transfer_amount {
+    // An earlier attempt might have transfered the money, but failed to save the transfer id
+    if let Some(transfer) = find_transfer(payment.id, client, stripe_token)? {
+        println!(
+            "Payment {} has already been transfered with {}",
+            payment.id, transfer.id
+        );
+        return Ok(transfer);
+    }
+    let transfer = transfer_request(
+        client,
+        stripe_token,
+        payment,
+        transfer_amount,
+        &charge.id,
+        &beneficiary.stripe_account,
+    )?;
+    println!(
+        "Transfered {} of charge {} to {} with {}",
+        transfer.amount, charge.id, transfer.destination, transfer.id
+    );
+    Ok(transfer)
+}
+
+/// Stores what Stripe has actually withheld and logs, if the invoice showed something else.
+/// The transfer does not depend on it, so failures are only logged.
+fn record_fee(payment: &Payment, balance_transaction: &BalanceTransaction, conn: &PgConnection) {
+    let fee_details: Vec<NewPaymentFee> = balance_transaction
+        .fee_details
+        .iter()
+        .map(|detail| NewPaymentFee {
+            payment_id: payment.id,
+            amount: PgMoney(detail.amount as i64),
+            currency: &detail.currency,
+            description: &detail.description,
+            fee_type: &detail.typ,
+        })
+        .collect();
+    let fee = PgMoney(balance_transaction.fee as i64);
+    if let Err(e) = db::save_fee(payment.id, fee, fee_details, conn) {
+        eprintln!("Fee of payment {} was not recorded. Err: {}", payment.id, e);
+    }
+    if let Some(estimated_fee) = payment.estimated_fee {
+        if estimated_fee != fee {
             println!(
-                "reduced_balance {:?} is NOT EQUAL to pending_amount {:?} - transfer_amount {:?}",
-                pending_amount_reduced, pending_amount, transfer_amount
+                "Fee of payment {} differs from the invoice: estimated {}, actual {}",
+                payment.id, estimated_fee.0, fee.0
             );
         }
     }
-    Ok(())
 }
 
-fn persist_payment(successful_payment: &SuccessfulPayment, conn: &db::UserDbConn) -> Payment {
-    let payload = successful_payment.get_payload();
+/// Returns None if the payment has already been persisted (Telegram re-delivered it)
+fn persist_payment(
+    successful_payment: &SuccessfulPayment,
+    conn: &PgConnection,
+) -> BotResult<Option<Payment>> {
+    let payload = successful_payment.get_payload()?;
+    let last_paid = Utc::now();
 
-    let last_paid = (Utc::now() + Duration::hours(2)).timestamp();
-    let new_last_total = payload.total;
-    let total = payload.totals_sum + new_last_total;
-    let mut update_user = UpdateUser::default();
+    // The payment is saved first. Its unique receipt_identifier guarantees,
+    // that the user's totals are only updated once per charge. Everything happens
+    // in one transaction, otherwise a re-delivery could not finish what has failed.
+    conn.transaction(|| {
+        let new_payment = NewPayment {
+            user_id: payload.user_id,
+            receipt_identifier: &successful_payment.provider_payment_charge_id,
+            payed_amount: PgMoney(payload.total),
+            payed_at: last_paid,
+            pub_id: payload.pub_id,
+            estimated_fee: payload.fee.map(PgMoney),
+            donor_paid_fee: payload.donor_pays_fee,
+        };
+        let payment = match db::save_payment(new_payment, conn)? {
+            Some(payment) => payment,
+            None => return Ok(None),
+        };
 
-    update_user.last_paid = Some(PgTimestamp(last_paid));
-    update_user.last_total = Some(PgMoney(new_last_total));
-    update_user.total = Some(PgMoney(total));
-    update_user.drink_count = Some(0);
-    db::update_user(payload.user_id, &update_user, &conn);
+        let new_last_total = payload.total;
+        let total = payload.totals_sum + new_last_total;
+        let mut update_user = UpdateUser::default();
 
-    let new_payment = NewPayment {
-        user_id: payload.user_id,
-        receipt_identifier: &successful_payment.provider_payment_charge_id,
-        payed_amount: PgMoney(payload.total),
-        payed_at: PgTimestamp(last_paid),
-    };
-    db::save_payment(new_payment, &conn)
+        update_user.last_paid = Some(last_paid);
+        update_user.last_total = Some(PgMoney(new_last_total));
+        update_user.total = Some(PgMoney(total));
+        db::update_user(payload.user_id, &update_user, conn)?;
+
+        db::settle_open_orders(payload.user_id, payload.chat_id, payment.id, conn)?;
+        Ok(Some(payment))
+    })
 }
 
-fn payment_intent_request(
+fn transfer_request(
     client: &Client,
     token: &str,
+    payment: &Payment,
     amount: i32,
-) -> Result<PaymentIntent, reqwest::Error> {
-    let destination_account = std::env::var("DESTINATION").unwrap();
-    let payment_intent_forminfo = &[
-        ("payment_method_types[]", "card"),
+    charge_id: &str,
+    destination_account: &str,
+) -> Result<Transfer, reqwest::Error> {
+    let transfer_forminfo = &[
         ("amount", &amount.to_string()),
         ("currency", "eur"),
-        ("transfer_data[destination]", &destination_account),
+        ("destination", destination_account),
+        ("source_transaction", charge_id),
+        ("transfer_group", &transfer_group(payment.id)),
     ];
+    // Stripe repeats the answer of a key, so a key per attempt is needed to retry a failed
+    // transfer. That it does not move the money twice is ensured by find_transfer.
+    let idempotency_key = format!(
+        "{}_{}",
+        transfer_group(payment.id),
+        payment.transfer_attempts
+    );
 
     client
-        .post("https://api.stripe.com/v1/payment_intents")
+        .post("https://api.stripe.com/v1/transfers")
         .bearer_auth(&token)
-        .form(payment_intent_forminfo)
+        .header("Idempotency-Key", idempotency_key)
+        .form(transfer_forminfo)
         .send()?
-        .json::<PaymentIntent>()
+        .error_for_status()?
+        .json::<Transfer>()
 }
 
-fn confirm_payment(
-    payment_id: &str,
+/// Returns the transfer of the payment, that is not reversed
+pub fn find_transfer(
+    payment_id: i32,
     client: &Client,
     token: &str,
-) -> Result<PaymentConfirmation, reqwest::Error> {
-    let confirm_payment_endpoint = format!(
-        "https://api.stripe.com/v1/payment_intents/{}/confirm",
-        payment_id
-    );
-    client
-        .post(&confirm_payment_endpoint)
-        .bearer_auth(&token)
-        // TODO: Use actual card-information
-        .form(&[("payment_method", "pm_card_visa")])
+) -> Result<Option<Transfer>, reqwest::Error> {
+    let transfers = client
+        .get("https://api.stripe.com/v1/transfers")
+        .bearer_auth(token)
+        .query(&[("transfer_group", transfer_group(payment_id))])
         .send()?
-        .json::<PaymentConfirmation>()
+        .error_for_status()?
+        .json::<TransferList>()?;
+    Ok(transfers
+        .data
+        .into_iter()
+        .find(|transfer| !transfer.reversed))
 }
 
 pub fn get_balance(client: &Client, token: &str) -> Result<Balance, reqwest::Error> {
@@ -122,14 +287,11 @@ pub fn get_balance(client: &Client, token: &str) -> Result<Balance, reqwest::Err
 }
 
 pub fn get_charge_by_payment(
-    successful_payment: &SuccessfulPayment,
+    charge_id: &str,
     client: &Client,
     token: &str,
 ) -> Result<ChargeResponse, reqwest::Error> {
-    let charge_endpoint = format!(
-        "https://api.stripe.com/v1/charges/{}",
-        successful_payment.provider_payment_charge_id
-    );
+    let charge_endpoint = format!("https://api.stripe.com/v1/charges/{}", charge_id);
     client
         .get(&charge_endpoint)
         .bearer_auth(token)
@@ -138,19 +300,154 @@ pub fn get_charge_by_payment(
         .json::<ChargeResponse>()
 }
 
-fn set_transfer_id_on_payment(payment_id: i32, transfer_id: &str, conn: &db::UserDbConn) {
-    db::save_transfer_id(payment_id, transfer_id, conn);
+fn set_payment_status(payment_id: i32, status: PaymentStatus, conn: &PgConnection) {
+    if let Err(e) = db::update_payment_status(payment_id, status, conn) {
+        eprintln!(
+            "Status of payment {} was not recorded. Err: {}",
+            payment_id, e
+        );
+    }
 }
 
 // Helpers
+// The transfer_group links the transfer (reported by the Stripe webhook) to the payment
+static TRANSFER_GROUP_PREFIX: &str = "payment_";
+
+pub fn transfer_group(payment_id: i32) -> String {
+    format!("{}{}", TRANSFER_GROUP_PREFIX, payment_id)
+}
+
+pub fn payment_id_from_transfer_group(group: &str) -> Option<i32> {
+    if !group.starts_with(TRANSFER_GROUP_PREFIX) {
+        return None;
+    }
+    group[TRANSFER_GROUP_PREFIX.len()..].parse().ok()
+}
+
 pub fn money_in_eur(money: i64) -> f32 {
     money as f32 / 100.00
 }
 
-pub fn calc_stripe_fee(damage: i64) -> i32 {
-    let fee_percentage = if is_test() { 0.029 } else { 0.014 };
-    let fee_fix_amount = 0.25;
-    let raw_damage = money_in_eur(damage);
-    let total_damage = raw_damage * fee_percentage + fee_fix_amount;
-    (total_damage * 100_f32) as i32
+// Stripe charges a higher rate for cards issued outside of the European Economic Area
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardRegion {
+    Eea,
+    NonEea,
+}
+
+/// The fee (in cents) Stripe withholds from a charge of amount cents.
+/// It is never more than the amount itself.
+pub fn stripe_fee(amount: i64, region: CardRegion, config: &Config) -> i64 {
+    let rate = match region {
+        CardRegion::Eea => config.fee_eea_rate,
+        CardRegion::NonEea => config.fee_non_eea_rate,
+    };
+    // The rate is in basis points, Stripe rounds to the nearest cent
+    let fee = (amount * rate + 5_000) / 10_000 + config.fee_fixed;
+    fee.min(amount)
+}
+
+/// The card is only known after the payment, so the invoice assumes a card from the EEA.
+/// Stripe's test cards are US-cards though.
+pub fn estimate_stripe_fee(amount: i64, config: &Config) -> i64 {
+    let region = if config.is_test() {
+        CardRegion::NonEea
+    } else {
+        CardRegion::Eea
+    };
+    stripe_fee(amount, region, config)
+}
+
+/// The amount to charge, so that net is left once Stripe has withheld its (estimated) fee
+pub fn gross_up(net: i64, config: &Config) -> i64 {
+    let mut gross = net;
+    // The fee grows with the charged amount, so it is approached from below.
+    // Ends, because the rate is below 100% (see Config::validate).
+    while gross - estimate_stripe_fee(gross, config) < net {
+        gross = net + estimate_stripe_fee(gross, config);
+    }
+    gross
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Environment;
+
+    // The default fees: 1.4% (EEA) or 2.9% (other cards) + 0.25€
+    fn config(environment: Environment) -> Config {
+        let mut config: Config = toml::from_str(
+            r#"
+            api_key = "key"
+            bot_username = "remoteDeckelTest_bot"
+            provider_token = "provider"
+            stripe_token = "stripe"
+            stripe_webhook_secret = "whsec"
+            "#,
+        )
+        .unwrap();
+        config.environment = environment;
+        config
+    }
+
+    #[test]
+    fn stripe_fee_depends_on_the_region_of_the_card() {
+        let config = config(Environment::Prod);
+        assert_eq!(stripe_fee(1000, CardRegion::Eea, &config), 14 + 25);
+        assert_eq!(stripe_fee(1000, CardRegion::NonEea, &config), 29 + 25);
+    }
+
+    #[test]
+    fn stripe_fee_is_rounded_to_the_nearest_cent() {
+        let config = config(Environment::Prod);
+        // 1.4% of 12.49€ are 17.486 cents, of 12.50€ 17.5 cents
+        assert_eq!(stripe_fee(1249, CardRegion::Eea, &config), 17 + 25);
+        assert_eq!(stripe_fee(1250, CardRegion::Eea, &config), 18 + 25);
+    }
+
+    #[test]
+    fn stripe_fee_is_never_more_than_the_amount() {
+        let config = config(Environment::Prod);
+        assert_eq!(stripe_fee(0, CardRegion::Eea, &config), 0);
+        assert_eq!(stripe_fee(10, CardRegion::Eea, &config), 10);
+        assert_eq!(stripe_fee(25, CardRegion::NonEea, &config), 25);
+        assert_eq!(stripe_fee(26, CardRegion::Eea, &config), 25);
+    }
+
+    #[test]
+    fn estimate_assumes_us_cards_in_test_mode() {
+        assert_eq!(estimate_stripe_fee(1000, &config(Environment::Prod)), 39);
+        assert_eq!(estimate_stripe_fee(1000, &config(Environment::Test)), 54);
+    }
+
+    #[test]
+    fn gross_up_leaves_the_net_amount() {
+        let config = config(Environment::Prod);
+        assert_eq!(gross_up(0, &config), 0);
+        assert_eq!(gross_up(1, &config), 26);
+        assert_eq!(gross_up(1000, &config), 1040);
+    }
+
+    #[test]
+    fn gross_up_charges_the_smallest_sufficient_amount() {
+        for environment in &[Environment::Prod, Environment::Test] {
+            let config = config(*environment);
+            for net in (0..5_000).step_by(7) {
+                let gross = gross_up(net, &config);
+                assert!(gross - estimate_stripe_fee(gross, &config) >= net);
+                if gross > 0 {
+                    let less = gross - 1;
+                    assert!(less - estimate_stripe_fee(less, &config) < net, "{}", net);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gross_up_converges_with_a_high_rate() {
+        let mut config = config(Environment::Prod);
+        config.fee_eea_rate = 5_000;
+        let gross = gross_up(1000, &config);
+        assert_eq!(gross - estimate_stripe_fee(gross, &config), 1000);
+    }
 }
@@ -0,0 +1,187 @@
+use crate::config::Config;
+use crate::db;
+use crate::error::{BotError, BotResult};
+use crate::i18n::{Language, Texts};
+use crate::models::{NewRefund, Payment, PaymentStatus, Refund, UpdateUser, User};
+use crate::payments;
+use crate::stripe_types;
+use crate::telegram_client::TelegramClient;
+use crate::telegram_types::ResponseMessage;
+use chrono::Utc;
+use diesel::pg::types::money::PgMoney;
+use diesel::{Connection, OptionalExtension};
+use reqwest::blocking::Client;
+use rocket_contrib::databases::diesel::PgConnection;
+
+/// Refunds a payment completely. Is triggered by an admin (e.g. the user has payed twice).
+/// - If the money has already been transfered, the transfer to the pub is reversed
+/// - The charge of the user is refunded and the refund is recorded
+/// - The totals of the user no longer contain the payment
+/// - The user is notified in the private chat
+pub fn refund_payment(
+    payment_id: i32,
+    reason: Option<&str>,
+    conn: &PgConnection,
+    config: &Config,
+) -> BotResult<Refund> {
+    let payment = claim_payment(payment_id, conn)?;
+
+    // Stripe is called without holding a lock. If a call fails, the payment stays RefundPending
+    // (nobody else touches it) and the admin can simply try again, the requests are idempotent.
+    let client = Client::builder().build()?;
+    // A transfer, that has not been reversed yet, is also found if its id has never been saved
+    let transfer_reversed =
+        match payments::find_transfer(payment.id, &client, &config.stripe_token)? {
+            Some(transfer) => {
+                let reversal =
+                    reverse_transfer(&transfer.id, payment.id, &client, &config.stripe_token)?;
+                println!(
+                    "Transfer {} of payment {} has been reversed with {}",
+                    reversal.transfer, payment.id, reversal.id
+                );
+                true
+            }
+            // Reversed earlier (e.g. in the Stripe dashboard or by a failed refund)
+            None => payment.transfer_id.is_some(),
+        };
+    let stripe_refund = create_refund(&payment, reason, &client, &config.stripe_token)?;
+    println!(
+        "Payment {} has been refunded with {} (status: {})",
+        payment.id, stripe_refund.id, stripe_refund.status
+    );
+
+    let (refund, user) = conn.transaction(|| {
+        let new_refund = NewRefund {
+            payment_id: payment.id,
+            stripe_refund_id: &stripe_refund.id,
+            amount: payment.payed_amount,
+            reason,
+            transfer_reversed,
+            refunded_at: Utc::now(),
+        };
+        let refund = db::save_refund(new_refund, conn)?;
+        // Stripe reports the refund with charge.refunded as well, whatever comes first sets the status
+        db::update_payment_status(payment.id, PaymentStatus::Refunded, conn)?;
+
+        // The user might have deleted the data in the meantime
+        let user = db::get_user_by_id(payment.user_id, conn).optional()?;
+        if let Some(user) = &user {
+            correct_totals(user, &payment, conn)?;
+        }
+        Ok((refund, user))
+    })?;
+
+    if let Some(user) = user {
+        notify_user(&user, &payment, conn, config);
+    }
+    Ok(refund)
+}
+
+// Marks the payment as RefundPending, so neither a second refund nor the transfer worker
+// can start on it. A refund, that has failed half way, can be continued.
+fn claim_payment(payment_id: i32, conn: &PgConnection) -> BotResult<Payment> {
+    conn.transaction(|| {
+        let payment = db::get_payment_for_update(payment_id, conn)?;
+        if payment.status == PaymentStatus::Refunded
+            || !payment
+                .status
+                .can_transition_to(PaymentStatus::RefundPending)
+        {
+            return Err(BotError::InvalidStatusTransition(
+                payment.status,
+                PaymentStatus::RefundPending,
+            ));
+        }
+        db::update_payment_status(payment.id, PaymentStatus::RefundPending, conn)
+    })
+}
+
+// The refunded amount no longer counts, the last donation is the latest one left
+fn correct_totals(user: &User, payment: &Payment, conn: &PgConnection) -> BotResult<()> {
+    let mut update_user = UpdateUser::default();
+    update_user.total = Some(PgMoney((user.total.0 - payment.payed_amount.0).max(0)));
+    match db::get_last_payment_of_user(user.id, conn)? {
+        Some(last_payment) => {
+            update_user.last_total = Some(last_payment.payed_amount);
+            update_user.last_paid = Some(last_payment.payed_at);
+        }
+        None => update_user.last_total = Some(PgMoney(0)),
+    }
+    db::update_user(user.id, &update_user, conn)?;
+    Ok(())
+}
+
+// The refund has already happened, so a failed notification is only logged
+fn notify_user(user: &User, payment: &Payment, conn: &PgConnection, config: &Config) {
+    let texts = Texts::new(Language::of_user(user));
+    let pub_name = payment
+        .pub_id
+        .and_then(|pub_id| db::get_pub_by_id(pub_id, conn).ok())
+        .map(|refunded_pub| refunded_pub.name)
+        .unwrap_or_default();
+    let text = texts.format(
+        "refund_notice",
+        &[
+            ("amount", &texts.money(payment.payed_amount.0)),
+            ("pub", &pub_name),
+        ],
+    );
+    // The id of the private chat with a user is the id of the user
+    let message = ResponseMessage::new("sendMessage".to_string(), user.id as i64, text);
+    if let Err(e) =
+        TelegramClient::from_config(config).and_then(|client| client.send_message(&message))
+    {
+        eprintln!(
+            "User with id: {} was not notified about the refund of payment {}. Err: {}",
+            user.id, payment.id, e
+        );
+    }
+}
+
+/// Takes the whole transfer back from the pub's account
+fn reverse_transfer(
+    transfer_id: &str,
+    payment_id: i32,
+    client: &Client,
+    token: &str,
+) -> Result<stripe_types::TransferReversal, reqwest::Error> {
+    let reversal_endpoint = format!(
+        "https://api.stripe.com/v1/transfers/{}/reversals",
+        transfer_id
+    );
+    client
+        .post(&reversal_endpoint)
+        .bearer_auth(token)
+        // Stripe does not repeat the reversal, if the request is sent again
+        .header(
+            "Idempotency-Key",
+            format!("reverse_transfer_{}", payment_id),
+        )
+        .send()?
+        .error_for_status()?
+        .json::<stripe_types::TransferReversal>()
+}
+
+fn create_refund(
+    payment: &Payment,
+    reason: Option<&str>,
+    client: &Client,
+    token: &str,
+) -> Result<stripe_types::Refund, reqwest::Error> {
+    let mut refund_forminfo = vec![
+        ("charge", payment.receipt_identifier.as_str()),
+        ("reason", "requested_by_customer"),
+    ];
+    // Stripe only knows a few reasons, the one of the admin is kept as metadata
+    if let Some(reason) = reason {
+        refund_forminfo.push(("metadata[reason]", reason));
+    }
+    client
+        .post("https://api.stripe.com/v1/refunds")
+        .bearer_auth(token)
+        .header("Idempotency-Key", format!("refund_{}", payment.id))
+        .form(&refund_forminfo)
+        .send()?
+        .error_for_status()?
+        .json::<stripe_types::Refund>()
+}
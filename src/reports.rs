@@ -0,0 +1,59 @@
+use crate::db;
+use crate::error::BotResult;
+use crate::models::Payment;
+use crate::payments::money_in_eur;
+use rocket_contrib::databases::diesel::PgConnection;
+
+/// Compares the fee shown on the invoice with the fee Stripe has actually withheld.
+/// Contains every payment whose fee is known (newest first) and a summary line.
+pub fn fee_report(conn: &PgConnection) -> BotResult<String> {
+    let payments = db::get_payments_with_fee(conn)?;
+    let mut lines: Vec<String> = payments.iter().map(fee_line).collect();
+
+    // Payments from before the estimate was stored can not be compared
+    let compared: Vec<(i64, i64)> = payments
+        .iter()
+        .filter_map(|payment| match (payment.estimated_fee, payment.fee) {
+            (Some(estimated_fee), Some(fee)) => Some((estimated_fee.0, fee.0)),
+            _ => None,
+        })
+        .collect();
+    let estimated_sum: i64 = compared
+        .iter()
+        .map(|(estimated_fee, _)| estimated_fee)
+        .sum();
+    let actual_sum: i64 = compared.iter().map(|(_, fee)| fee).sum();
+    let differing = compared
+        .iter()
+        .filter(|(estimated_fee, fee)| estimated_fee != fee)
+        .count();
+    lines.push(format!(
+        "{} payments compared, {} with a different fee: estimated {:.2}€, actual {:.2}€, difference {:+.2}€",
+        compared.len(),
+        differing,
+        money_in_eur(estimated_sum),
+        money_in_eur(actual_sum),
+        money_in_eur(actual_sum - estimated_sum)
+    ));
+    Ok(lines.join("\n"))
+}
+
+fn fee_line(payment: &Payment) -> String {
+    let fee = payment.fee.map(|fee| fee.0).unwrap_or_default();
+    let estimate = match payment.estimated_fee {
+        Some(estimated_fee) => format!(
+            "estimated {:.2}€, difference {:+.2}€",
+            money_in_eur(estimated_fee.0),
+            money_in_eur(fee - estimated_fee.0)
+        ),
+        None => "no estimate".to_string(),
+    };
+    format!(
+        "Payment {} ({}): {:.2}€, fee {:.2}€, {}",
+        payment.id,
+        payment.payed_at.format("%Y-%m-%d"),
+        money_in_eur(payment.payed_amount.0),
+        money_in_eur(fee),
+        estimate
+    )
+}
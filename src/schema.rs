@@ -1,11 +1,112 @@
+table! {
+    conversations (user_id, chat_id) {
+        user_id -> Int4,
+        chat_id -> Int8,
+        state -> Varchar,
+        state_changed_at -> Timestamptz,
+    }
+}
+
+table! {
+    drinks (id) {
+        id -> Int4,
+        name -> Varchar,
+        emoji -> Varchar,
+        price -> Money,
+        active -> Bool,
+    }
+}
+
+table! {
+    group_members (chat_id, user_id) {
+        chat_id -> Int8,
+        user_id -> Int4,
+        last_seen_at -> Timestamptz,
+    }
+}
+
+table! {
+    orders (id) {
+        id -> Int4,
+        user_id -> Int4,
+        drink_id -> Int4,
+        price -> Money,
+        ordered_at -> Timestamptz,
+        payment_id -> Nullable<Int4>,
+        chat_id -> Int8,
+    }
+}
+
 table! {
     payments (id) {
         id -> Int4,
         user_id -> Int4,
         receipt_identifier -> Varchar,
         payed_amount -> Money,
-        payed_at -> Timestamp,
+        payed_at -> Timestamptz,
         transfer_id -> Nullable<Varchar>,
+        status -> Varchar,
+        status_changed_at -> Timestamptz,
+        pub_id -> Nullable<Int4>,
+        transfer_attempts -> Int2,
+        last_error -> Nullable<Varchar>,
+        next_attempt_at -> Nullable<Timestamptz>,
+        estimated_fee -> Nullable<Money>,
+        fee -> Nullable<Money>,
+        donor_paid_fee -> Bool,
+    }
+}
+
+table! {
+    payment_fees (id) {
+        id -> Int4,
+        payment_id -> Int4,
+        amount -> Money,
+        currency -> Varchar,
+        description -> Varchar,
+        fee_type -> Varchar,
+    }
+}
+
+table! {
+    processed_updates (update_id) {
+        update_id -> Int4,
+        processed_at -> Timestamptz,
+    }
+}
+
+table! {
+    pubs (id) {
+        id -> Int4,
+        name -> Varchar,
+        stripe_account -> Varchar,
+        logo_url -> Nullable<Varchar>,
+        active -> Bool,
+        timezone -> Varchar,
+    }
+}
+
+table! {
+    refunds (id) {
+        id -> Int4,
+        payment_id -> Int4,
+        stripe_refund_id -> Varchar,
+        amount -> Money,
+        reason -> Nullable<Varchar>,
+        transfer_reversed -> Bool,
+        refunded_at -> Timestamptz,
+    }
+}
+
+table! {
+    spending_limits (id) {
+        id -> Int4,
+        user_id -> Nullable<Int4>,
+        max_tab -> Nullable<Money>,
+        max_invoice -> Nullable<Money>,
+        max_daily -> Nullable<Money>,
+        max_weekly -> Nullable<Money>,
+        max_monthly -> Nullable<Money>,
     }
 }
 
@@ -15,15 +116,39 @@ table! {
         name -> Varchar,
         first_name -> Varchar,
         last_name -> Varchar,
-        drink_count -> Int2,
-        price -> Money,
-        last_paid -> Timestamp,
+        price -> Nullable<Money>,
+        last_paid -> Timestamptz,
         last_total -> Money,
         total -> Money,
+        pub_id -> Nullable<Int4>,
+        active -> Bool,
+        timezone -> Nullable<Varchar>,
+        language -> Nullable<Varchar>,
+        donor_pays_fee -> Bool,
     }
 }
 
+joinable!(conversations -> users (user_id));
+joinable!(group_members -> users (user_id));
+joinable!(orders -> drinks (drink_id));
+joinable!(orders -> payments (payment_id));
+joinable!(orders -> users (user_id));
+joinable!(payment_fees -> payments (payment_id));
+joinable!(payments -> pubs (pub_id));
+joinable!(refunds -> payments (payment_id));
+joinable!(spending_limits -> users (user_id));
+joinable!(users -> pubs (pub_id));
+
 allow_tables_to_appear_in_same_query!(
+    conversations,
+    drinks,
+    group_members,
+    orders,
+    payment_fees,
     payments,
+    processed_updates,
+    pubs,
+    refunds,
+    spending_limits,
     users,
 );
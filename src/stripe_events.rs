@@ -0,0 +1,320 @@
+use crate::db;
+use crate::models::{Payment, PaymentStatus};
+use crate::payments::payment_id_from_transfer_group;
+use crate::stripe_types::*;
+use hmac::{Hmac, Mac};
+use rocket_contrib::databases::diesel::PgConnection;
+use sha2::Sha256;
+
+// Events signed earlier than this (in seconds) are rejected to prevent replay attacks
+pub static SIGNATURE_TOLERANCE: i64 = 300;
+
+/// Checks the Stripe-Signature header as described in https://stripe.com/docs/webhooks/signatures
+/// The header looks like "t=1492774577,v1=5257a869...,v1=..." where t is the unix timestamp
+/// of signing and every v1 is a hex encoded HMAC-SHA256 of "{t}.{payload}".
+pub fn is_signature_valid(signature_header: &str, payload: &str, secret: &str, now: i64) -> bool {
+    let mut timestamp = None;
+    let mut signatures = Vec::new();
+    for part in signature_header.split(',') {
+        let mut key_value = part.trim().splitn(2, '=');
+        match (key_value.next(), key_value.next()) {
+            (Some("t"), Some(value)) => timestamp = value.parse::<i64>().ok(),
+            (Some("v1"), Some(value)) => signatures.push(value),
+            _ => {}
+        }
+    }
+    let timestamp = match timestamp {
+        Some(timestamp) => timestamp,
+        None => return false,
+    };
+    if (now - timestamp).abs() > SIGNATURE_TOLERANCE {
+        return false;
+    }
+    let signed_payload = format!("{}.{}", timestamp, payload);
+    signatures
+        .iter()
+        .filter_map(|signature| decode_hex(signature))
+        .any(|signature| {
+            let mut mac = match Hmac::<Sha256>::new_varkey(secret.as_bytes()) {
+                Ok(mac) => mac,
+                Err(_) => return false,
+            };
+            mac.input(signed_payload.as_bytes());
+            mac.verify(&signature).is_ok()
+        })
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Updates the status of the payment, the event belongs to.
+/// Events of other types and events for unknown payments are only logged.
+pub fn handle_event(event: Event, conn: &PgConnection) -> serde_json::Result<()> {
+    match event.typ.as_str() {
+        "charge.succeeded" => {
+            // Payments are persisted as received, as soon as Telegram reports them.
+            // So there is nothing to change, but a missing payment is worth to be noticed.
+            let charge: EventCharge = serde_json::from_value(event.data.object)?;
+            if let Err(e) = db::get_payment_by_receipt(&charge.id, conn) {
+                eprintln!(
+                    "No payment found for charge {} of Stripe event {}. Err: {}",
+                    charge.id, event.id, e
+                );
+            }
+        }
+        "charge.refunded" => {
+            let charge: EventCharge = serde_json::from_value(event.data.object)?;
+            let payment = db::get_payment_by_receipt(&charge.id, conn);
+            update_status(payment, PaymentStatus::Refunded, &event.id, conn);
+        }
+        "transfer.created" => {
+            let transfer: EventTransfer = serde_json::from_value(event.data.object)?;
+            if let Some(payment) = payment_of_transfer(&transfer, &event.id, conn) {
+                update_status(payment, PaymentStatus::Transferred, &event.id, conn);
+            }
+        }
+        // The payment_intent events of the former charge flow do not occur, since the user's charge
+        // is the source_transaction of the transfer. A pub only loses the money, if the transfer
+        // is reversed. Our own refunds reverse it as well, but then the payment is no longer transferred.
+        "transfer.reversed" => {
+            let transfer: EventTransfer = serde_json::from_value(event.data.object)?;
+            if !transfer.reversed {
+                println!(
+                    "Transfer {} of event {} has only been reversed partially",
+                    transfer.id, event.id
+                );
+                return Ok(());
+            }
+            match payment_of_transfer(&transfer, &event.id, conn) {
+                Some(Ok(payment)) if payment.status == PaymentStatus::Transferred => {
+                    // The transfer id is kept, so the transfer worker does not simply transfer again
+                    eprintln!(
+                        "Transfer {} of payment {} has been reversed",
+                        transfer.id, payment.id
+                    );
+                    update_status(Ok(payment), PaymentStatus::TransferFailed, &event.id, conn);
+                }
+                Some(Ok(payment)) => println!(
+                    "Reversal of transfer {} ignored, payment {} is {}",
+                    transfer.id, payment.id, payment.status
+                ),
+                Some(Err(e)) => {
+                    update_status(Err(e), PaymentStatus::TransferFailed, &event.id, conn)
+                }
+                None => {}
+            }
+        }
+        other => println!("Ignored Stripe event {} of type {}", event.id, other),
+    }
+    Ok(())
+}
+
+// None if the transfer has not been made for a payment
+fn payment_of_transfer(
+    transfer: &EventTransfer,
+    event_id: &str,
+    conn: &PgConnection,
+) -> Option<Result<Payment, diesel::result::Error>> {
+    match transfer
+        .transfer_group
+        .as_deref()
+        .and_then(payment_id_from_transfer_group)
+    {
+        Some(payment_id) => Some(db::get_payment_by_id(payment_id, conn)),
+        None => {
+            println!(
+                "Transfer {} of event {} does not belong to a payment",
+                transfer.id, event_id
+            );
+            None
+        }
+    }
+}
+
+fn update_status(
+    payment: Result<Payment, diesel::result::Error>,
+    new_status: PaymentStatus,
+    event_id: &str,
+    conn: &PgConnection,
+) {
+    match payment {
+        Ok(payment) => {
+            if let Err(e) = db::update_payment_status(payment.id, new_status, conn) {
+                eprintln!("Stripe event {} was not applied. Err: {}", event_id, e);
+            }
+        }
+        Err(e) => eprintln!(
+            "No payment found for Stripe event {} (status: {}). Err: {}",
+            event_id, new_status, e
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static SECRET: &str = "whsec_test";
+    static PAYLOAD: &str = r#"{"id":"evt_1","type":"charge.refunded"}"#;
+    static NOW: i64 = 1_600_000_000;
+
+    fn sign(timestamp: i64, payload: &str, secret: &str) -> String {
+        let mut mac = Hmac::<Sha256>::new_varkey(secret.as_bytes()).unwrap();
+        mac.input(format!("{}.{}", timestamp, payload).as_bytes());
+        mac.result()
+            .code()
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+
+    fn header(timestamp: i64, signature: &str) -> String {
+        format!("t={},v1={}", timestamp, signature)
+    }
+
+    #[test]
+    fn accepts_a_valid_signature() {
+        let signature = sign(NOW, PAYLOAD, SECRET);
+        assert!(is_signature_valid(
+            &header(NOW, &signature),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+    }
+
+    #[test]
+    fn accepts_any_matching_v1_signature() {
+        let signature = sign(NOW, PAYLOAD, SECRET);
+        let header = format!(
+            "t={}, v1={}, v1={}, v0=abc",
+            NOW,
+            "00".repeat(32),
+            signature
+        );
+        assert!(is_signature_valid(&header, PAYLOAD, SECRET, NOW));
+    }
+
+    #[test]
+    fn rejects_a_tampered_payload() {
+        let signature = sign(NOW, PAYLOAD, SECRET);
+        let tampered = PAYLOAD.replace("evt_1", "evt_2");
+        assert!(!is_signature_valid(
+            &header(NOW, &signature),
+            &tampered,
+            SECRET,
+            NOW
+        ));
+    }
+
+    #[test]
+    fn rejects_a_tampered_signature() {
+        let mut signature = sign(NOW, PAYLOAD, SECRET);
+        let last = if signature.ends_with('0') { "1" } else { "0" };
+        signature.replace_range(63.., last);
+        assert!(!is_signature_valid(
+            &header(NOW, &signature),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+    }
+
+    #[test]
+    fn rejects_a_tampered_timestamp() {
+        let signature = sign(NOW, PAYLOAD, SECRET);
+        assert!(!is_signature_valid(
+            &header(NOW + 1, &signature),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+    }
+
+    #[test]
+    fn rejects_another_secret() {
+        let signature = sign(NOW, PAYLOAD, "whsec_other");
+        assert!(!is_signature_valid(
+            &header(NOW, &signature),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+    }
+
+    #[test]
+    fn rejects_old_and_future_events() {
+        let old = NOW - SIGNATURE_TOLERANCE - 1;
+        let future = NOW + SIGNATURE_TOLERANCE + 1;
+        let within = NOW - SIGNATURE_TOLERANCE;
+        assert!(!is_signature_valid(
+            &header(old, &sign(old, PAYLOAD, SECRET)),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+        assert!(!is_signature_valid(
+            &header(future, &sign(future, PAYLOAD, SECRET)),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+        assert!(is_signature_valid(
+            &header(within, &sign(within, PAYLOAD, SECRET)),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+    }
+
+    #[test]
+    fn rejects_malformed_headers() {
+        let signature = sign(NOW, PAYLOAD, SECRET);
+        assert!(!is_signature_valid("", PAYLOAD, SECRET, NOW));
+        assert!(!is_signature_valid(
+            &format!("v1={}", signature),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+        assert!(!is_signature_valid(
+            &format!("t={}", NOW),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+        assert!(!is_signature_valid(
+            &header(NOW, "not hex"),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+        assert!(!is_signature_valid(
+            &header(NOW, &signature[1..]),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+        assert!(!is_signature_valid(
+            &format!("t=abc,v1={}", signature),
+            PAYLOAD,
+            SECRET,
+            NOW
+        ));
+    }
+
+    #[test]
+    fn decode_hex_reads_pairs_of_digits() {
+        assert_eq!(decode_hex("00ff10"), Some(vec![0, 255, 16]));
+        assert_eq!(decode_hex("0"), None);
+        assert_eq!(decode_hex("zz"), None);
+        assert_eq!(decode_hex("éé"), None);
+    }
+}
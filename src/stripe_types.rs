@@ -152,3 +152,71 @@ pub struct FeeDetails {
     #[serde(rename(deserialize = "type"))]
     pub typ: String,
 }
+
+#[derive(Debug, Deserialize)]
+pub struct Transfer {
+    pub id: String,
+    pub amount: i32,
+    pub currency: String,
+    pub destination: String,
+    pub source_transaction: Option<String>,
+    pub transfer_group: Option<String>,
+    pub reversed: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferList {
+    pub data: Vec<Transfer>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TransferReversal {
+    pub id: String,
+    pub amount: i32,
+    pub transfer: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Refund {
+    pub id: String,
+    pub amount: i32,
+    pub charge: Option<String>,
+    // pending, succeeded, failed or canceled
+    pub status: String,
+}
+
+// Webhook-Types
+#[derive(Debug, Deserialize)]
+pub struct Event {
+    pub id: String,
+    #[serde(rename(deserialize = "type"))]
+    pub typ: String,
+    pub created: i64,
+    pub livemode: bool,
+    pub data: EventData,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventData {
+    // The shape depends on the type of the event
+    pub object: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventCharge {
+    pub id: String,
+    pub amount: i32,
+    pub amount_refunded: i32,
+    pub refunded: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventTransfer {
+    pub id: String,
+    pub amount: i32,
+    pub destination: String,
+    pub transfer_group: Option<String>,
+    // Only true once the whole amount has been reversed
+    #[serde(default)]
+    pub reversed: bool,
+}
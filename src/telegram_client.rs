@@ -0,0 +1,139 @@
+use crate::config::Config;
+use crate::error::{BotError, BotResult};
+use crate::telegram_types::*;
+use reqwest::blocking::Client;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::thread;
+use std::time::Duration;
+
+// A rate-limited call is repeated this many times, before the error is returned
+static MAX_RETRIES: u32 = 3;
+// Waiting longer than this (in seconds) would block the request for too long
+static MAX_RETRY_AFTER: u64 = 30;
+// Must be longer than the timeout of a getUpdates long-poll
+static REQUEST_TIMEOUT: Duration = Duration::from_secs(60);
+
+pub fn bot_method_url(method: &str, api_key: &str) -> String {
+    let telegram_base_url = "https://api.telegram.org/bot";
+    format!("{}{}/{}", telegram_base_url, api_key, method)
+}
+
+/// Calls the Bot API directly. Needed for everything that can not be done
+/// with the one method, that fits into the webhook-response.
+pub struct TelegramClient {
+    client: Client,
+    api_key: String,
+}
+
+impl TelegramClient {
+    pub fn new(api_key: String) -> BotResult<Self> {
+        let client = Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .build()
+            .map_err(|e| BotError::Telegram(e.to_string()))?;
+        Ok(TelegramClient { client, api_key })
+    }
+
+    pub fn from_config(config: &Config) -> BotResult<Self> {
+        TelegramClient::new(config.api_key.to_string())
+    }
+
+    pub fn send_message(&self, message: &ResponseMessage) -> BotResult<Message> {
+        self.call("sendMessage", message)
+    }
+
+    // The message must have a message_id (see ResponseMessage::edit)
+    pub fn edit_message_text(&self, message: &ResponseMessage) -> BotResult<Message> {
+        if message.message_id.is_none() {
+            return Err(BotError::Telegram(
+                "editMessageText needs a message_id".to_string(),
+            ));
+        }
+        self.call("editMessageText", message)
+    }
+
+    pub fn delete_message(&self, chat_id: i64, message_id: i32) -> BotResult<bool> {
+        let delete_message = DeleteMessage {
+            chat_id,
+            message_id,
+        };
+        self.call("deleteMessage", &delete_message)
+    }
+
+    pub fn send_invoice(&self, invoice: &InvoiceReplyMessage) -> BotResult<Message> {
+        self.call("sendInvoice", invoice)
+    }
+
+    pub fn answer_callback_query(&self, answer: &AnswerCallbackQueryMessage) -> BotResult<bool> {
+        self.call("answerCallbackQuery", answer)
+    }
+
+    // Long-polling (getUpdates) does not work while a webhook is set
+    pub fn delete_webhook(&self) -> BotResult<bool> {
+        self.call("deleteWebhook", &serde_json::json!({}))
+    }
+
+    /// Waits up to timeout seconds for updates newer than offset.
+    /// All updates before offset are confirmed and not sent again.
+    pub fn get_updates(&self, offset: i32, timeout: u64) -> BotResult<Vec<Update>> {
+        let get_updates = GetUpdates { offset, timeout };
+        self.call("getUpdates", &get_updates)
+    }
+
+    /// Calls the method, that would otherwise have been sent as webhook-response.
+    /// The json must contain the name of the method (e.g. ResponseMessage.method).
+    pub fn execute(&self, method_json: &str) -> BotResult<()> {
+        let params: serde_json::Value = serde_json::from_str(method_json)?;
+        match params["method"].as_str() {
+            Some(method) => {
+                self.call::<_, serde_json::Value>(method, &params)?;
+                Ok(())
+            }
+            // Nothing to answer (e.g. "{}")
+            None => Ok(()),
+        }
+    }
+
+    /// Telegram answers every call with an ApiResponse. If the bot sends too many messages,
+    /// the response tells how many seconds to wait (retry_after) before the call is repeated.
+    fn call<P: Serialize, R: DeserializeOwned>(&self, method: &str, params: &P) -> BotResult<R> {
+        let url = bot_method_url(method, &self.api_key);
+        let mut retries = 0;
+        loop {
+            let response: ApiResponse<R> = self
+                .client
+                .post(&url)
+                .json(params)
+                .send()
+                .and_then(|response| response.json())
+                .map_err(|e| BotError::Telegram(format!("{} failed: {}", method, e)))?;
+
+            if response.ok {
+                return response
+                    .result
+                    .ok_or_else(|| BotError::Telegram(format!("{} returned no result", method)));
+            }
+
+            let retry_after = response
+                .parameters
+                .as_ref()
+                .and_then(|parameters| parameters.retry_after);
+            match retry_after {
+                Some(seconds) if retries < MAX_RETRIES && seconds <= MAX_RETRY_AFTER => {
+                    println!("{} is rate-limited, retrying in {}s", method, seconds);
+                    thread::sleep(Duration::from_secs(seconds));
+                    retries += 1;
+                }
+                _ => {
+                    return Err(BotError::Telegram(format!(
+                        "{} failed ({}): {}",
+                        method,
+                        response.error_code.unwrap_or_default(),
+                        response.description.unwrap_or_default()
+                    )))
+                }
+            }
+        }
+    }
+}
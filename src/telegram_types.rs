@@ -1,10 +1,16 @@
 use crate::bot_types::Payload;
+use crate::error::{BotError, BotResult};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Update {
     pub update_id: i32,
     pub message: Option<Message>,
+    pub edited_message: Option<Message>,
+    pub callback_query: Option<CallbackQuery>,
+    // The user has blocked or unblocked the bot
+    pub my_chat_member: Option<ChatMemberUpdated>,
+    pub shipping_query: Option<ShippingQuery>,
     pub pre_checkout_query: Option<PreCheckoutQuery>,
     pub successful_payment: Option<SuccessfulPayment>,
 }
@@ -30,6 +36,49 @@ pub struct PreCheckoutQuery {
     pub invoice_payload: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CallbackQuery {
+    pub id: String,
+    pub from: User,
+    // Is None if the message is too old
+    pub message: Option<Message>,
+    pub data: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatMemberUpdated {
+    pub chat: Chat,
+    pub from: User,
+    pub date: i32,
+    pub old_chat_member: ChatMember,
+    pub new_chat_member: ChatMember,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ChatMember {
+    pub user: User,
+    // "creator", "administrator", "member", "restricted", "left" or "kicked"
+    pub status: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShippingQuery {
+    pub id: String,
+    pub from: User,
+    pub invoice_payload: String,
+    pub shipping_address: ShippingAddress,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShippingAddress {
+    pub country_code: String,
+    pub state: String,
+    pub city: String,
+    pub street_line1: String,
+    pub street_line2: String,
+    pub post_code: String,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SuccessfulPayment {
     pub currency: String,
@@ -40,11 +89,10 @@ pub struct SuccessfulPayment {
     pub provider_payment_charge_id: String,
 }
 impl SuccessfulPayment {
-    pub fn get_payload(&self) -> Payload {
-        match serde_json::from_str(&self.invoice_payload) {
-            Ok(payload) => payload,
-            Err(e) => panic!("Could not parse pre_checkout_query.payload. Error: {}", e),
-        }
+    pub fn get_payload(&self) -> BotResult<Payload> {
+        serde_json::from_str(&self.invoice_payload).map_err(|e| {
+            BotError::TelegramParse(format!("Invalid successful_payment.payload: {}", e))
+        })
     }
 }
 
@@ -60,7 +108,8 @@ pub struct User {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Chat {
-    pub id: i32,
+    // Ids of groups are negative and can be longer than 32 bits
+    pub id: i64,
     #[serde(rename = "type")]
     pub typ: String,
     pub title: Option<String>,
@@ -68,6 +117,12 @@ pub struct Chat {
     pub first_name: Option<String>,
     pub last_name: Option<String>,
 }
+impl Chat {
+    // "private", "group", "supergroup" or "channel"
+    pub fn is_group(&self) -> bool {
+        self.typ == "group" || self.typ == "supergroup"
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Sticker {
@@ -79,33 +134,88 @@ pub struct Sticker {
     pub emoji: Option<String>,
 }
 
+// Every Bot API call is answered with this
+#[derive(Debug, Deserialize)]
+pub struct ApiResponse<T> {
+    pub ok: bool,
+    pub result: Option<T>,
+    pub description: Option<String>,
+    pub error_code: Option<i32>,
+    pub parameters: Option<ResponseParameters>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ResponseParameters {
+    pub migrate_to_chat_id: Option<i64>,
+    // Seconds to wait, if the request has been rate-limited
+    pub retry_after: Option<u64>,
+}
+
 // Send-Types
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ResponseMessage {
+    // sendMessage or editMessageText
     pub method: String,
-    pub chat_id: i32,
+    pub chat_id: i64,
+    // Only set when an existing message is edited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message_id: Option<i32>,
     pub text: String,
-    pub reply_markup: Option<ReplyKeyboardMarkup>,
+    pub reply_markup: Option<ReplyMarkup>,
 }
 impl ResponseMessage {
-    pub fn new(method: String, chat_id: i32, text: String) -> Self {
+    pub fn new(method: String, chat_id: i64, text: String) -> Self {
         ResponseMessage {
             method,
             chat_id,
+            message_id: None,
             text,
             reply_markup: None,
         }
     }
-    pub fn keyboard(mut self, keyboard: ReplyKeyboardMarkup) -> ResponseMessage {
-        self.reply_markup = Some(keyboard);
+    pub fn keyboard(mut self, keyboard: InlineKeyboardMarkup) -> ResponseMessage {
+        self.reply_markup = Some(ReplyMarkup::Inline(keyboard));
+        self
+    }
+    // Hides the reply keyboard, that was shown below the input field before inline keyboards
+    // were used. Can only be sent with a new message, not with an edit.
+    pub fn remove_keyboard(mut self) -> ResponseMessage {
+        self.reply_markup = Some(ReplyMarkup::Remove(ReplyKeyboardRemove {
+            remove_keyboard: true,
+        }));
+        self
+    }
+    // Replaces text and keyboard of the message instead of sending a new one
+    pub fn edit(mut self, message_id: i32) -> ResponseMessage {
+        self.method = "editMessageText".to_string();
+        self.message_id = Some(message_id);
         self
     }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
-pub struct ReplyKeyboardMarkup {
-    pub keyboard: Vec<Vec<String>>,
-    pub resize_keyboard: bool,
+pub struct GetUpdates {
+    pub offset: i32,
+    // Seconds to wait for updates (long-polling)
+    pub timeout: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteMessage {
+    pub chat_id: i64,
+    pub message_id: i32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ReplyMarkup {
+    Inline(InlineKeyboardMarkup),
+    Remove(ReplyKeyboardRemove),
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ReplyKeyboardRemove {
+    pub remove_keyboard: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -113,28 +223,44 @@ pub struct InlineKeyboardMarkup {
     pub inline_keyboard: Vec<Vec<InlineKeyboardButton>>,
 }
 impl InlineKeyboardMarkup {
-    pub fn new(amount_to_pay: f32) -> Self {
+    // The pay-button of an invoice, it must be the first button
+    pub fn new(pay_label: &str) -> Self {
         InlineKeyboardMarkup {
             inline_keyboard: vec![vec![InlineKeyboardButton {
-                text: format!("Jetzt {:.2}€ spenden", amount_to_pay),
-                pay: true,
+                text: pay_label.to_string(),
+                callback_data: None,
+                pay: Some(true),
             }]],
         }
     }
 }
 
+// Exactly one of the optional fields must be set
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InlineKeyboardButton {
     pub text: String,
+    // 1-64 Bytes, sent back in the callback_query when the button is pressed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback_data: Option<String>,
     // pub url: String COULD BE used to open stripe-payout-plugin if nothing else works
-    pub pay: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pay: Option<bool>,
+}
+impl InlineKeyboardButton {
+    pub fn callback(text: &str, callback_data: String) -> Self {
+        InlineKeyboardButton {
+            text: text.to_string(),
+            callback_data: Some(callback_data),
+            pay: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct InvoiceReplyMessage {
     // Must be sendInvoice
     pub method: String,
-    pub chat_id: i32,
+    pub chat_id: i64,
     // 1-32 Chars
     pub title: String,
     // 1-255 Chars
@@ -177,18 +303,51 @@ pub struct PreCheckoutQueryResponseMessage {
     pub error_message: Option<String>,
 }
 impl PreCheckoutQueryResponseMessage {
-    pub fn new(id: &str, is_checkout_granted: bool) -> PreCheckoutQueryResponseMessage {
+    // While there are no options and a donation can not be out of stock,
+    // a checkout is only declined with an error_message (e.g. a spending limit is exceeded)
+    pub fn new(id: &str, error_message: Option<String>) -> PreCheckoutQueryResponseMessage {
         PreCheckoutQueryResponseMessage {
             method: "answerPreCheckoutQuery".to_string(),
             pre_checkout_query_id: id.to_string(),
-            ok: is_checkout_granted,
-            // While there are no options and a donation can not be out of stock,
-            // we only send an error if the total is above a certain threshold (for security reasons).
-            error_message: if is_checkout_granted {
-                None
-            } else {
-                Some("The total was to high. Transaction denied for security purposes.".to_string())
-            },
+            ok: error_message.is_none(),
+            error_message,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AnswerCallbackQueryMessage {
+    // answerCallbackQuery
+    pub method: String,
+    pub callback_query_id: String,
+    pub text: Option<String>,
+}
+impl AnswerCallbackQueryMessage {
+    pub fn new(id: &str, text: Option<String>) -> AnswerCallbackQueryMessage {
+        AnswerCallbackQueryMessage {
+            method: "answerCallbackQuery".to_string(),
+            callback_query_id: id.to_string(),
+            text,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ShippingQueryResponseMessage {
+    // answerShippingQuery
+    pub method: String,
+    pub shipping_query_id: String,
+    pub ok: bool,
+    pub error_message: Option<String>,
+}
+impl ShippingQueryResponseMessage {
+    // Donations are never shipped, so every shipping_query is declined
+    pub fn decline(id: &str, error_message: String) -> ShippingQueryResponseMessage {
+        ShippingQueryResponseMessage {
+            method: "answerShippingQuery".to_string(),
+            shipping_query_id: id.to_string(),
+            ok: false,
+            error_message: Some(error_message),
         }
     }
 }
@@ -0,0 +1,45 @@
+use crate::config::Config;
+use crate::db;
+use crate::payments;
+use chrono::Utc;
+use rocket_contrib::databases::diesel::{Connection, PgConnection};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Duration;
+
+// How often the worker looks for transfers, that need to be retried
+static RETRY_INTERVAL: Duration = Duration::from_secs(60);
+// Processed updates are only kept for this many days, re-deliveries come much earlier
+static PROCESSED_UPDATES_RETENTION: i64 = 3;
+
+/// Starts a background thread, which periodically retries failed or skipped transfers
+/// to the pubs and forgets old processed updates.
+/// Every run opens its own connection, so a lost connection is no problem.
+pub fn start(database_url: String, config: Config) -> thread::JoinHandle<()> {
+    thread::spawn(move || loop {
+        thread::sleep(RETRY_INTERVAL);
+        let conn = match PgConnection::establish(&database_url) {
+            Ok(conn) => conn,
+            Err(e) => {
+                eprintln!("Transfer worker could not connect to DB. Err: {}", e);
+                continue;
+            }
+        };
+        // A panic (e.g. a failing DB-query) must not stop the worker for good
+        let run = panic::catch_unwind(AssertUnwindSafe(|| {
+            payments::retry_transfers(&conn, &config)
+        }));
+        match run {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => eprintln!("Transfer worker could not retry transfers. Err: {}", e),
+            Err(_) => eprintln!("Transfer worker panicked while retrying transfers"),
+        }
+        let processed_before = Utc::now() - chrono::Duration::days(PROCESSED_UPDATES_RETENTION);
+        if let Err(e) = db::delete_processed_updates_before(processed_before, &conn) {
+            eprintln!(
+                "Transfer worker could not delete processed updates. Err: {}",
+                e
+            );
+        }
+    })
+}
@@ -0,0 +1,359 @@
+use crate::bot_context::BotContext;
+use crate::bot_types::{Keyboards, Payload, RequestType};
+use crate::config::Config;
+use crate::error::{BotError, BotResult};
+use crate::i18n::{Language, Texts};
+use crate::limits::Limits;
+use crate::payments;
+use crate::telegram_client::TelegramClient;
+use crate::telegram_types::{
+    self, AnswerCallbackQueryMessage, PreCheckoutQueryResponseMessage, ResponseMessage,
+    ShippingQueryResponseMessage, Update,
+};
+use crate::{db, models};
+use chrono::Utc;
+use diesel::Connection;
+use rocket_contrib::databases::diesel::PgConnection;
+
+/// Handles one update, no matter if it came in via webhook or long-polling.
+/// Returns the json of the Bot API method, that answers the update ("{}" if there is nothing to answer).
+/// If there is a chat to answer to, errors are reported there instead of being returned.
+pub fn handle_update(update: &Update, conn: &PgConnection, config: &Config) -> BotResult<String> {
+    let chat_id = update
+        .message
+        .as_ref()
+        .or(update.edited_message.as_ref())
+        .or(update
+            .callback_query
+            .as_ref()
+            .and_then(|query| query.message.as_ref()))
+        .map(|message| message.chat.id);
+    let sender = update
+        .message
+        .as_ref()
+        .or(update.edited_message.as_ref())
+        .and_then(|message| message.from.as_ref())
+        .or(update.callback_query.as_ref().map(|query| &query.from));
+    match process_update_once(update, conn, config) {
+        Ok(json_response_str) => Ok(json_response_str),
+        Err(e) => match chat_id {
+            Some(chat_id) => {
+                eprintln!(
+                    "Update {} could not be handled. Err: {}",
+                    update.update_id, e
+                );
+                create_error_message(chat_id, &Texts::new(language_of(sender, conn)))
+            }
+            None => Err(e),
+        },
+    }
+}
+
+/// Payments are idempotent anyway (the charge id is unique), so they are not marked.
+/// Every other update is marked in the same transaction it is handled in. If handling fails
+/// (or the process dies), the mark is rolled back with everything else and a re-delivery
+/// is handled again.
+fn process_update_once(update: &Update, conn: &PgConnection, config: &Config) -> BotResult<String> {
+    let is_payment = update
+        .message
+        .as_ref()
+        .map_or(false, |message| message.successful_payment.is_some());
+    if is_payment {
+        return process_update(update, conn, config);
+    }
+    let response = conn.transaction(|| {
+        if !db::mark_update_processed(update.update_id, conn)? {
+            println!("Update {} has already been processed", update.update_id);
+            return Ok("{}".to_string());
+        }
+        process_update(update, conn, config)
+    });
+    // The webhook-response of a pressed button is used for the edit, so the query is answered
+    // separately. That is a call to Telegram, so it is made after the transaction is committed.
+    if let Some(query) = &update.callback_query {
+        if query.message.is_some() && query.data.is_some() {
+            if let Err(e) = answer_callback_query(query, config) {
+                eprintln!("Could not answer callback_query {}. Err: {}", query.id, e);
+            }
+        }
+    }
+    response
+}
+
+fn process_update(update: &Update, conn: &PgConnection, config: &Config) -> BotResult<String> {
+    if let Some(message) = &update.message {
+        return match message.successful_payment.as_ref() {
+            None => create_response_message(message, conn, config),
+            Some(successful_payment) => {
+                // The user has already payed, so the thanks are sent anyway
+                if let Err(e) = payments::pay(&successful_payment, conn, config) {
+                    eprintln!("Could not process payment. Err: {}", e);
+                }
+                let texts = Texts::new(language_of(message.from.as_ref(), conn));
+                let keyboards = Keyboards::init(
+                    &db::get_active_drinks(conn)?,
+                    &db::get_active_pubs(conn)?,
+                    &texts,
+                    message.chat.is_group(),
+                );
+                create_successful_payment_response(
+                    &successful_payment.get_payload()?,
+                    &texts,
+                    &keyboards,
+                )
+            }
+        };
+    }
+    if let Some(query) = &update.pre_checkout_query {
+        return create_answer_pre_checkout_response(query, conn);
+    }
+    if let Some(edited_message) = &update.edited_message {
+        // Most edits in a group are not meant for the bot, so they are not answered there
+        if edited_message.chat.is_group() {
+            return Ok("{}".to_string());
+        }
+        let texts = Texts::new(language_of(edited_message.from.as_ref(), conn));
+        return create_edited_message_response(edited_message, &texts);
+    }
+    if let Some(query) = &update.callback_query {
+        return create_callback_query_response(query, conn, config);
+    }
+    if let Some(chat_member_update) = &update.my_chat_member {
+        return handle_chat_member_update(chat_member_update, conn);
+    }
+    if let Some(query) = &update.shipping_query {
+        return create_answer_shipping_query_response(query, conn);
+    }
+    Err(BotError::TelegramParse(format!(
+        "Update {} has no supported update type",
+        update.update_id
+    )))
+}
+
+fn create_response_message(
+    incoming_message: &telegram_types::Message,
+    conn: &PgConnection,
+    config: &Config,
+) -> BotResult<String> {
+    let telegram_user = match &incoming_message.from {
+        Some(user) => user,
+        None => {
+            return Err(BotError::TelegramParse(
+                "message has no sender (from = None)".to_string(),
+            ))
+        }
+    };
+    let current_user = get_or_create_user(&telegram_user, conn)?;
+    let user_text = get_text_from_message(&incoming_message);
+    let timestamp = incoming_message.date as i64;
+    let mut bot_context = BotContext::new(
+        current_user,
+        conn,
+        config,
+        &incoming_message.chat,
+        None,
+        user_text,
+        timestamp,
+    );
+    match bot_context.get_request_type(&incoming_message)? {
+        Some(request_type) => bot_context.handle_request(request_type),
+        None => Ok("{}".to_string()),
+    }
+}
+
+/// A pressed inline-button. Its callback_data is the action id of the RequestType.
+/// The message carrying the button is edited to show the answer.
+fn create_callback_query_response(
+    query: &telegram_types::CallbackQuery,
+    conn: &PgConnection,
+    config: &Config,
+) -> BotResult<String> {
+    let (message, data) = match (&query.message, &query.data) {
+        (Some(message), Some(data)) => (message, data),
+        // Without the message there is nothing to edit, so only the loading-animation is stopped
+        _ => return create_answer_callback_query_response(query),
+    };
+    // The query itself is answered, once the transaction is committed (see process_update_once)
+
+    let current_user = get_or_create_user(&query.from, conn)?;
+    let timestamp = Utc::now().timestamp();
+    let mut bot_context = BotContext::new(
+        current_user,
+        conn,
+        config,
+        &message.chat,
+        Some(message.message_id),
+        data.to_string(),
+        timestamp,
+    );
+    bot_context.handle_request(RequestType::from_action_id(data))
+}
+
+fn get_or_create_user(
+    telegram_user: &telegram_types::User,
+    conn: &PgConnection,
+) -> BotResult<models::User> {
+    match get_user_from_db(&telegram_user, conn) {
+        Ok(user) => Ok(user),
+        Err(diesel::result::Error::NotFound) => {
+            let new_user = persist_new_user(&telegram_user, conn)?;
+            println!(
+                "New user: {} with id: {} has been created",
+                new_user.name, new_user.id
+            );
+            Ok(new_user)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn create_answer_pre_checkout_response(
+    query: &telegram_types::PreCheckoutQuery,
+    conn: &PgConnection,
+) -> BotResult<String> {
+    // TODO: Do something more useful (maybe like persisting) query.payload
+    let payload: Payload = serde_json::from_str(&query.invoice_payload).map_err(|e| {
+        BotError::TelegramParse(format!("Invalid pre_checkout_query.payload: {}", e))
+    })?;
+    // Limits may have changed, or other payments have been made, since the invoice was sent.
+    // Like the limits of orders, they apply to the tab, not to the fee on top.
+    let exceeded = Limits::of_user(payload.user_id, conn)?.check_invoice(
+        payload.tab(),
+        payload.user_id,
+        Utc::now(),
+        conn,
+    )?;
+    let error_message = exceeded.map(|exceeded| {
+        let texts = Texts::new(language_of(Some(&query.from), conn));
+        exceeded.text(&texts)
+    });
+    let answer_query = PreCheckoutQueryResponseMessage::new(&query.id, error_message);
+    Ok(serde_json::to_string(&answer_query)?)
+}
+
+// Orders (or anything else) are not changed afterwards, the user is asked to send a new message
+fn create_edited_message_response(
+    edited_message: &telegram_types::Message,
+    texts: &Texts,
+) -> BotResult<String> {
+    let method = "sendMessage".to_string();
+    let response_message =
+        ResponseMessage::new(method, edited_message.chat.id, texts.get("edited_message"));
+    Ok(serde_json::to_string(&response_message)?)
+}
+
+// Stops the loading-animation on the pressed inline-button
+fn create_answer_callback_query_response(
+    query: &telegram_types::CallbackQuery,
+) -> BotResult<String> {
+    let answer_query = AnswerCallbackQueryMessage::new(&query.id, None);
+    Ok(serde_json::to_string(&answer_query)?)
+}
+
+fn answer_callback_query(query: &telegram_types::CallbackQuery, config: &Config) -> BotResult<()> {
+    let answer_query = AnswerCallbackQueryMessage::new(&query.id, None);
+    TelegramClient::from_config(config)?.answer_callback_query(&answer_query)?;
+    Ok(())
+}
+
+fn create_answer_shipping_query_response(
+    query: &telegram_types::ShippingQuery,
+    conn: &PgConnection,
+) -> BotResult<String> {
+    let texts = Texts::new(language_of(Some(&query.from), conn));
+    let answer_query =
+        ShippingQueryResponseMessage::decline(&query.id, texts.get("shipping_declined"));
+    Ok(serde_json::to_string(&answer_query)?)
+}
+
+/// In a private chat "kicked" means, that the user has blocked the bot.
+/// The user (and the tab) is kept, but marked inactive until the bot is unblocked.
+fn handle_chat_member_update(
+    chat_member_update: &telegram_types::ChatMemberUpdated,
+    conn: &PgConnection,
+) -> BotResult<String> {
+    if chat_member_update.chat.typ == "private" {
+        let user_id = chat_member_update.from.id;
+        match chat_member_update.new_chat_member.status.as_str() {
+            "kicked" => {
+                db::set_user_active(user_id, false, conn)?;
+                println!("User with id: {} has blocked the bot", user_id);
+            }
+            "member" => {
+                db::set_user_active(user_id, true, conn)?;
+                println!("User with id: {} has unblocked the bot", user_id);
+            }
+            _ => {}
+        }
+    }
+    // Blocked users can not be answered
+    Ok("{}".to_string())
+}
+
+fn create_successful_payment_response(
+    payload: &Payload,
+    texts: &Texts,
+    keyboards: &Keyboards,
+) -> BotResult<String> {
+    let method = "sendMessage".to_string();
+    let text = texts.format("payment_thanks", &[("amount", &texts.money(payload.total))]);
+    let response_message = ResponseMessage::new(method, payload.chat_id, text)
+        .keyboard(keyboards.get_keyboard(RequestType::PayYes));
+    Ok(serde_json::to_string(&response_message)?)
+}
+
+// Sent without keyboard, because building it might be what failed
+fn create_error_message(chat_id: i64, texts: &Texts) -> BotResult<String> {
+    let method = "sendMessage".to_string();
+    let response_message = ResponseMessage::new(method, chat_id, texts.get("error"));
+    Ok(serde_json::to_string(&response_message)?)
+}
+
+// The language the user has chosen, or the one of the Telegram app if the user is not known yet
+fn language_of(telegram_user: Option<&telegram_types::User>, conn: &PgConnection) -> Language {
+    match telegram_user {
+        Some(telegram_user) => match get_user_from_db(telegram_user, conn) {
+            Ok(user) => Language::of_user(&user),
+            Err(_) => Language::from_telegram(telegram_user.language_code.as_deref()),
+        },
+        None => Language::DEFAULT,
+    }
+}
+
+fn get_user_from_db(
+    telegram_user: &telegram_types::User,
+    conn: &PgConnection,
+) -> Result<models::User, diesel::result::Error> {
+    db::get_user_by_id(telegram_user.id, conn)
+}
+
+fn persist_new_user(
+    telegram_user: &telegram_types::User,
+    conn: &PgConnection,
+) -> BotResult<models::User> {
+    let user_name = match telegram_user.username {
+        Some(ref username) => username,
+        None => "undefined",
+    };
+    let first_name = telegram_user.first_name.to_string();
+    let last_name = match telegram_user.last_name {
+        Some(ref last_name) => last_name,
+        None => "undefined",
+    };
+    let new_user = models::NewUser {
+        id: telegram_user.id,
+        name: &user_name,
+        first_name: &first_name,
+        last_name: last_name,
+        // The bot starts speaking the language of the Telegram app
+        language: Some(Language::from_telegram(telegram_user.language_code.as_deref()).code()),
+    };
+    Ok(db::save_user(new_user, conn)?)
+}
+
+fn get_text_from_message(telegram_message: &telegram_types::Message) -> String {
+    match &telegram_message.text {
+        Some(text) => text.to_string(),
+        None => "".to_string(),
+    }
+}
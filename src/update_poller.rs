@@ -0,0 +1,71 @@
+use crate::config::Config;
+use crate::error::BotResult;
+use crate::telegram_client::TelegramClient;
+use crate::update_handler;
+use rocket_contrib::databases::diesel::{Connection, PgConnection};
+use std::panic::{self, AssertUnwindSafe};
+use std::thread;
+use std::time::Duration;
+
+// Telegram keeps the getUpdates-request open for this many seconds, if there are no updates
+static POLL_TIMEOUT: u64 = 30;
+// Pause after a failed poll, so a broken connection does not end in a busy loop
+static ERROR_DELAY: Duration = Duration::from_secs(5);
+
+/// Starts a background thread, which receives updates via getUpdates instead of the webhook.
+/// Every update goes through the same handling as a webhook-update. The answer, that would
+/// have been the webhook-response, is sent with the TelegramClient.
+pub fn start(database_url: String, config: Config) -> BotResult<thread::JoinHandle<()>> {
+    let client = TelegramClient::from_config(&config)?;
+    client.delete_webhook()?;
+    println!("Webhook deleted, polling for updates");
+
+    Ok(thread::spawn(move || {
+        // Updates with a lower update_id are confirmed with the next poll
+        let mut offset = 0;
+        loop {
+            let updates = match client.get_updates(offset, POLL_TIMEOUT) {
+                Ok(updates) => updates,
+                Err(e) => {
+                    eprintln!("Could not poll updates. Err: {}", e);
+                    thread::sleep(ERROR_DELAY);
+                    continue;
+                }
+            };
+            if updates.is_empty() {
+                continue;
+            }
+            let conn = match PgConnection::establish(&database_url) {
+                Ok(conn) => conn,
+                Err(e) => {
+                    eprintln!("Update poller could not connect to DB. Err: {}", e);
+                    thread::sleep(ERROR_DELAY);
+                    continue;
+                }
+            };
+            for update in updates {
+                offset = update.update_id + 1;
+                // A panic must not stop the poller for good, the update is skipped
+                let answer = panic::catch_unwind(AssertUnwindSafe(|| {
+                    update_handler::handle_update(&update, &conn, &config)
+                        .and_then(|method_json| client.execute(&method_json))
+                }));
+                match answer {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => eprintln!(
+                        "Update {} could not be handled. Err: {}",
+                        update.update_id, e
+                    ),
+                    Err(_) => {
+                        eprintln!(
+                            "Update poller panicked while handling update {}",
+                            update.update_id
+                        );
+                        // The connection might be left in a transaction, the next poll opens a new one
+                        break;
+                    }
+                }
+            }
+        }
+    }))
+}